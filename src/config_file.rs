@@ -0,0 +1,186 @@
+//! Default arguments read from configuration files.
+//!
+//! Two files are read, if they exist, and their arguments are placed
+//! before those given on the command line, so that the command line
+//! takes precedence:
+//!
+//! 1. `$FNR_CONFIG_PATH`, or `~/.config/fnr/config` when it's unset.
+//!    This holds one argument per line, like ripgrep's config file.
+//!    Blank lines and lines starting with `#` are ignored.
+//!
+//! 2. `.fnr.toml`, found in the search root or its closest parent
+//!    directory. Keys are long flag names, e.g.
+//!
+//!    ```toml
+//!    exclude = ["vendor", "node_modules"]
+//!    context = 4
+//!    hidden = true
+//!    ```
+//!
+//!    This comes with whatever is being searched, so only the flags
+//!    in `REPOSITORY_KEYS`, which change what's shown and which files
+//!    are searched, may be set. Anything which writes, reviews or
+//!    chooses the pattern must be given on the command line.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use toml::{Table, Value};
+
+const CONFIG_PATH_ENV: &str = "FNR_CONFIG_PATH";
+const REPOSITORY_CONFIG_NAME: &str = ".fnr.toml";
+
+/// Flags which may be set by `.fnr.toml`.
+const REPOSITORY_KEYS: &[&str] = &[
+    "after",
+    "all-files",
+    "before",
+    "color",
+    "compact",
+    "context",
+    "exclude",
+    "hidden",
+    "include",
+    "quiet",
+    "sort",
+    "stats",
+];
+
+/// Arguments from all configuration files which apply to a search of
+/// `search_root`.
+pub fn args(search_root: &Path) -> Result<Vec<OsString>> {
+    let mut args = vec![];
+
+    if let Some(path) = user_config_path() {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        args.extend(parse_args_file(&contents));
+    }
+
+    if let Some(path) = find_repository_config(search_root) {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let toml_args = parse_toml_file(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        args.extend(toml_args);
+    }
+
+    Ok(args)
+}
+
+/// An explicitly given path must exist, while the default location
+/// is optional. Setting `$FNR_CONFIG_PATH` to be empty disables it.
+fn user_config_path() -> Option<PathBuf> {
+    match std::env::var_os(CONFIG_PATH_ENV) {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+                })?;
+
+            Some(config_dir.join("fnr").join("config")).filter(|path| path.is_file())
+        }
+    }
+}
+
+fn find_repository_config(search_root: &Path) -> Option<PathBuf> {
+    let search_root = fs::canonicalize(search_root).ok()?;
+
+    search_root
+        .ancestors()
+        .map(|dir| dir.join(REPOSITORY_CONFIG_NAME))
+        .find(|path| path.is_file())
+}
+
+fn parse_args_file(contents: &str) -> Vec<OsString> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(OsString::from)
+        .collect()
+}
+
+fn parse_toml_file(contents: &str) -> Result<Vec<OsString>> {
+    let table: Table = contents.parse()?;
+
+    let mut args = vec![];
+    for (key, value) in &table {
+        if !REPOSITORY_KEYS.contains(&key.as_str()) {
+            bail!(
+                "'{}' can't be set in {}, only display and file filtering options can",
+                key,
+                REPOSITORY_CONFIG_NAME
+            );
+        }
+
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(format!("--{}", key)),
+                Value::Boolean(false) => {}
+                Value::String(s) => args.push(format!("--{}={}", key, s)),
+                Value::Integer(n) => args.push(format!("--{}={}", key, n)),
+                _ => bail!("unsupported value for '{}'", key),
+            }
+        }
+    }
+
+    Ok(args.into_iter().map(OsString::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_file() {
+        let contents = "\
+# Skip vendored code
+--exclude
+vendor
+
+  --context=4
+";
+        assert_eq!(
+            parse_args_file(contents),
+            vec!["--exclude", "vendor", "--context=4"]
+        );
+    }
+
+    #[test]
+    fn test_parse_toml_file() {
+        let contents = r#"
+exclude = ["vendor", "node_modules"]
+context = 4
+hidden = true
+quiet = false
+color = "always"
+"#;
+        assert_eq!(
+            parse_toml_file(contents).unwrap(),
+            vec![
+                "--color=always",
+                "--context=4",
+                "--exclude=vendor",
+                "--exclude=node_modules",
+                "--hidden",
+            ]
+        );
+
+        assert!(parse_toml_file("context = 4.5").is_err());
+        assert!(parse_toml_file("write = true").is_err());
+        assert!(parse_toml_file("dirty = \"allow\"").is_err());
+        assert!(parse_toml_file("replace-file = \"evil.txt\"").is_err());
+        assert!(parse_toml_file("[exclude]\nvendor = true").is_err());
+    }
+}
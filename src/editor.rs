@@ -0,0 +1,139 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context, Result};
+use tempfile::Builder;
+
+use crate::replace::PromptInput;
+use crate::search::Match;
+
+/// The user's preferred editor, as a program and its arguments.
+fn editor_command() -> Vec<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+
+    editor.split_whitespace().map(str::to_owned).collect()
+}
+
+/// Let the user edit the replacement of `search_match` in `path`.
+///
+/// The whole file is opened in an editor with `replacement` staged in
+/// place of the lines of the match, so the surrounding code is
+/// visible. Once the editor
+/// exits, the edited file is compared against what was staged to find
+/// the new replacement, which may span any number of lines.
+///
+/// Returns `None` if the editor exits unsuccessfully, which skips the
+/// replacement.
+pub fn edit_replacement(
+    path: &Path,
+    search_match: &Match,
+    replacement: &str,
+    input: PromptInput,
+) -> Result<Option<String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut staged: Vec<&str> = contents.split_inclusive('\n').collect();
+
+    let line_num = search_match.line.0;
+    let num_lines = search_match.num_lines();
+    let idx = (line_num as usize)
+        .checked_sub(1)
+        .filter(|&idx| idx + num_lines <= staged.len())
+        .context("line is outside of file")?;
+    staged.splice(idx..idx + num_lines, [replacement]);
+
+    // Keep the extension so the editor can pick the right syntax.
+    let mut builder = Builder::new();
+    let suffix = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut temp_file = builder.prefix("fnr-").suffix(&suffix).tempfile()?;
+    temp_file.write_all(staged.concat().as_bytes())?;
+    temp_file.flush()?;
+
+    let command = editor_command();
+    ensure!(!command.is_empty(), "no editor configured, set $EDITOR");
+
+    let mut editor = Command::new(&command[0]);
+    editor
+        .args(&command[1..])
+        .arg(format!("+{}", line_num))
+        .arg(temp_file.path());
+
+    // Standard input may already be used up, e.g. by a list of paths.
+    if let PromptInput::Tty = input {
+        editor.stdin(PromptInput::open_tty()?);
+    }
+
+    let status = editor
+        .status()
+        .with_context(|| format!("failed to run editor '{}'", command[0]))?;
+
+    if !status.success() {
+        return Ok(None);
+    }
+
+    let edited = fs::read_to_string(temp_file.path())?;
+    let edited: Vec<&str> = edited.split_inclusive('\n').collect();
+
+    match edited_region(&staged, &edited, idx) {
+        Some(region) => Ok(Some(region.concat())),
+        None => bail!("only the replacement at line {} may be edited", line_num),
+    }
+}
+
+/// Find the lines which replaced `staged[idx]` in `edited`, or `None`
+/// if any other lines were changed.
+fn edited_region<'a>(staged: &[&str], edited: &'a [&'a str], idx: usize) -> Option<&'a [&'a str]> {
+    let prefix = staged
+        .iter()
+        .zip(edited)
+        .take(idx)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let suffix = staged[idx + 1..]
+        .iter()
+        .rev()
+        .zip(edited[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if prefix == idx && suffix == staged.len() - idx - 1 {
+        Some(&edited[prefix..edited.len() - suffix])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edited_region() {
+        let staged = ["a\n", "b\n", "c\n"];
+
+        assert_eq!(
+            edited_region(&staged, &["a\n", "b\n", "c\n"], 1),
+            Some(&["b\n"][..])
+        );
+        assert_eq!(
+            edited_region(&staged, &["a\n", "x\n", "y\n", "c\n"], 1),
+            Some(&["x\n", "y\n"][..])
+        );
+        assert_eq!(edited_region(&staged, &["a\n", "c\n"], 1), Some(&[][..]));
+        assert_eq!(
+            edited_region(&staged, &["a\n", "b\n", "b\n", "c\n"], 1),
+            Some(&["b\n", "b\n"][..])
+        );
+
+        // Changes outside of the staged line
+        assert_eq!(edited_region(&staged, &["x\n", "b\n", "c\n"], 1), None);
+        assert_eq!(edited_region(&staged, &["a\n", "b\n"], 1), None);
+    }
+}
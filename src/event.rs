@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::search::Match;
+
+/// Something that happened while finding and replacing, passed to an
+/// [`EventSink`].
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A file with matches is about to be processed.
+    File { path: &'a Path, num_matches: usize },
+
+    /// A match and the line that would replace it.
+    ///
+    /// `swaps` lists each `(matched text, replacement)` exchange made
+    /// on the line when swapping, and is otherwise empty.
+    Match {
+        path: &'a Path,
+        search_match: &'a Match,
+        replacement: &'a str,
+        swaps: &'a [(String, String)],
+    },
+
+    /// Accepted replacements were written to a file.
+    Replaced { path: &'a Path, num_replaced: usize },
+
+    /// A path whose name matches, and what it would be renamed to.
+    Rename { from: &'a Path, to: &'a Path },
+
+    /// A path was renamed.
+    Renamed { from: &'a Path, to: &'a Path },
+
+    /// A file or path was left alone, such as a file with uncommitted
+    /// changes or a rename onto a path which already exists.
+    Skipped { path: &'a Path, reason: &'a str },
+
+    /// Something went wrong, but processing carried on.
+    Error {
+        path: Option<&'a Path>,
+        message: &'a str,
+    },
+}
+
+/// Receives events as files are processed, in place of printing them
+/// to the terminal.
+pub trait EventSink {
+    fn handle(&mut self, event: Event<'_>) -> Result<()>;
+}
+
+impl<F> EventSink for F
+where
+    F: FnMut(Event<'_>) -> Result<()>,
+{
+    fn handle(&mut self, event: Event<'_>) -> Result<()> {
+        self(event)
+    }
+}
+
+/// A change waiting on a decision.
+#[derive(Debug, Clone, Copy)]
+pub enum Proposal<'a> {
+    /// Replace the matching line with `replacement`.
+    Replace {
+        path: &'a Path,
+        search_match: &'a Match,
+        replacement: &'a str,
+    },
+
+    /// Rename `from` to `to`.
+    Rename { from: &'a Path, to: &'a Path },
+}
+
+/// A decision on a [`Proposal`], made by a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Ignore,
+    /// Stop without writing the current file or processing any more.
+    Terminate,
+}
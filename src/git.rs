@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::search::Match;
+
+/// Run git with the given arguments, returning its standard output.
+fn git<I, S>(args: I) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+
+    ensure!(
+        output.status.success(),
+        "git failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(output.stdout)
+}
+
+/// Run git in the repository containing `dir`, rather than the one
+/// the current directory is in.
+pub fn git_in<I, S>(dir: &Path, args: I) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut all_args = vec![OsStr::new("-C").to_owned(), dir.as_os_str().to_owned()];
+    all_args.extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+
+    git(all_args)
+}
+
+/// The directory to run git in to look at `paths`, which are assumed
+/// to be in the same repository.
+fn working_dir(paths: &[PathBuf]) -> &Path {
+    match paths.first() {
+        Some(path) if path.is_dir() => path,
+        Some(path) => path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new(".")),
+        None => Path::new("."),
+    }
+}
+
+/// Pathspecs are relative to the directory git runs in, so make them
+/// absolute.
+fn pathspecs(paths: &[PathBuf]) -> Result<Vec<OsString>> {
+    let cwd = std::env::current_dir()?;
+    Ok(paths
+        .iter()
+        .map(|path| cwd.join(path).into_os_string())
+        .collect())
+}
+
+fn repository_root(dir: &Path) -> Result<PathBuf> {
+    let stdout = git_in(dir, ["rev-parse", "--show-toplevel"])?;
+    let root = String::from_utf8(stdout).context("repository path is not valid UTF-8")?;
+
+    Ok(PathBuf::from(root.trim_end()))
+}
+
+/// List files under `paths` which have changed relative to `rev`
+/// (including uncommitted changes and untracked files), or which are
+/// staged when `staged` is set. Deleted files are not included, and
+/// paths are absolute.
+pub fn changed_files(
+    rev: Option<&str>,
+    staged: bool,
+    paths: &[PathBuf],
+) -> Result<HashSet<PathBuf>> {
+    let dir = working_dir(paths);
+    let root = repository_root(dir)?;
+
+    let mut args = vec![
+        OsStr::new("diff").to_owned(),
+        "--name-only".into(),
+        "--no-renames".into(),
+        "--diff-filter=d".into(),
+        "-z".into(),
+    ];
+    if staged {
+        args.push("--cached".into());
+    }
+    if let Some(rev) = rev {
+        args.push(rev.into());
+    }
+    args.push("--".into());
+    args.extend(pathspecs(paths)?);
+
+    let stdout = git_in(dir, args)?;
+    let mut files: HashSet<_> = split_names(&stdout).map(|name| root.join(name)).collect();
+
+    // Untracked files can't be staged, but are changes all the same.
+    if !staged {
+        files.extend(untracked_files(dir, &root, paths)?);
+    }
+
+    Ok(files)
+}
+
+/// Files under `paths` which git doesn't track, leaving out ignored
+/// ones.
+fn untracked_files(dir: &Path, root: &Path, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut args = vec![
+        OsStr::new("ls-files").to_owned(),
+        "--others".into(),
+        "--exclude-standard".into(),
+        "--full-name".into(),
+        "-z".into(),
+        "--".into(),
+    ];
+    args.extend(pathspecs(paths)?);
+
+    let stdout = git_in(dir, args)?;
+    Ok(split_names(&stdout).map(|name| root.join(name)).collect())
+}
+
+/// Split the NUL separated paths git prints with `-z`.
+fn split_names(stdout: &[u8]) -> impl Iterator<Item = String> + '_ {
+    stdout
+        .split(|&b| b == b'\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+}
+
+/// Lines added or modified in each file relative to a revision,
+/// according to `git diff`.
+#[derive(Debug, Default)]
+pub struct ChangedLines {
+    files: HashMap<PathBuf, Vec<RangeInclusive<u64>>>,
+}
+
+impl ChangedLines {
+    pub fn load(rev: &str, paths: &[PathBuf]) -> Result<ChangedLines> {
+        let dir = working_dir(paths);
+        let root = repository_root(dir)?;
+
+        let mut args = vec![
+            OsStr::new("-c").to_owned(),
+            "core.quotepath=off".into(),
+            "diff".into(),
+            "--no-color".into(),
+            "--no-ext-diff".into(),
+            "--no-prefix".into(),
+            "-U0".into(),
+            rev.into(),
+            "--".into(),
+        ];
+        args.extend(pathspecs(paths)?);
+
+        let stdout = git_in(dir, args)?;
+        let mut changed_lines = Self::parse(&root, &String::from_utf8_lossy(&stdout));
+
+        // Every line of an untracked file is new.
+        for path in untracked_files(dir, &root, paths)? {
+            changed_lines.files.insert(path, vec![1..=u64::MAX]);
+        }
+
+        Ok(changed_lines)
+    }
+
+    fn parse(root: &Path, diff: &str) -> ChangedLines {
+        let mut changed_lines = ChangedLines::default();
+        let mut ranges = None;
+        // Lines left in the current hunk, from the old and new files.
+        // Outside of hunks, lines are part of a file's header.
+        let mut remaining = (0, 0);
+
+        for line in diff.lines() {
+            if remaining != (0, 0) {
+                match line.as_bytes().first() {
+                    Some(b'-') => remaining.0 -= 1,
+                    Some(b'+') => remaining.1 -= 1,
+                    Some(b' ') => remaining = (remaining.0 - 1, remaining.1 - 1),
+                    // "\ No newline at end of file"
+                    _ => (),
+                }
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix("+++ ") {
+                let name = Self::unquote_path(name);
+                ranges = if name == "/dev/null" {
+                    None
+                } else {
+                    Some(changed_lines.files.entry(root.join(name)).or_default())
+                };
+            } else if let Some(hunk) = line.strip_prefix("@@ ") {
+                let mut sides = hunk.split(' ');
+                let old = sides
+                    .next()
+                    .and_then(|it| it.strip_prefix('-'))
+                    .and_then(Self::parse_hunk_range);
+                let new = sides
+                    .next()
+                    .and_then(|it| it.strip_prefix('+'))
+                    .and_then(Self::parse_hunk_range);
+
+                let count = |range: &Option<RangeInclusive<u64>>| {
+                    range.as_ref().map_or(0, |r| r.end() + 1 - r.start())
+                };
+                remaining = (count(&old), count(&new));
+
+                if let (Some(ranges), Some(range)) = (ranges.as_mut(), new) {
+                    ranges.push(range);
+                }
+            }
+        }
+
+        changed_lines
+    }
+
+    /// Undo the quoting git applies to unusual paths in a file header,
+    /// along with the tab it adds after paths containing spaces.
+    fn unquote_path(name: &str) -> String {
+        let name = name.strip_suffix('\t').unwrap_or(name);
+        let quoted = match name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+        {
+            Some(quoted) => quoted.as_bytes(),
+            None => return name.to_owned(),
+        };
+
+        let mut unquoted = vec![];
+        let mut bytes = quoted.iter().copied();
+        while let Some(b) = bytes.next() {
+            if b != b'\\' {
+                unquoted.push(b);
+                continue;
+            }
+
+            let unescaped = match bytes.next() {
+                Some(b'a') => 0x07,
+                Some(b'b') => 0x08,
+                Some(b'f') => 0x0c,
+                Some(b'n') => b'\n',
+                Some(b'r') => b'\r',
+                Some(b't') => b'\t',
+                Some(b'v') => 0x0b,
+                // Bytes outside of ASCII are written as three octal
+                // digits.
+                Some(digit @ b'0'..=b'7') => bytes
+                    .by_ref()
+                    .take(2)
+                    .fold(digit - b'0', |byte, digit| byte << 3 | (digit - b'0')),
+                Some(other) => other,
+                None => break,
+            };
+            unquoted.push(unescaped);
+        }
+
+        String::from_utf8_lossy(&unquoted).into_owned()
+    }
+
+    /// Parse the `start[,count]` line range of one side of a hunk
+    /// header. Returns `None` for sides without any lines, such as
+    /// the new side of hunks which only remove lines.
+    fn parse_hunk_range(range: &str) -> Option<RangeInclusive<u64>> {
+        let (start, count) = match range.split_once(',') {
+            Some((start, count)) => (start.parse::<u64>().ok()?, count.parse::<u64>().ok()?),
+            None => (range.parse::<u64>().ok()?, 1),
+        };
+
+        if count == 0 {
+            None
+        } else {
+            Some(start..=start + count - 1)
+        }
+    }
+
+    /// Drop matches on lines of `path` which were not added or modified.
+    pub fn retain_changed(&self, path: &Path, matches: &mut Vec<Match>) {
+        let ranges = std::fs::canonicalize(path)
+            .ok()
+            .and_then(|path| self.files.get(&path));
+
+        match ranges {
+            Some(ranges) => {
+                matches.retain(|m| ranges.iter().any(|range| range.contains(&m.line.0)))
+            }
+            None => matches.clear(),
+        }
+    }
+}
+
+/// Tracks which files have staged or unstaged changes, or are
+/// untracked. Each repository's status is loaded the first time one
+/// of its files is checked.
+#[derive(Debug, Default)]
+pub struct DirtyFiles {
+    repositories: Mutex<HashMap<PathBuf, Arc<HashSet<PathBuf>>>>,
+}
+
+impl DirtyFiles {
+    /// Whether `path` is in a git repository and has uncommitted
+    /// changes or is untracked.
+    pub fn is_dirty(&self, path: &Path) -> Result<bool> {
+        let path = std::fs::canonicalize(path)?;
+        let root = match path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(".git").exists())
+        {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+
+        let dirty = {
+            let mut repositories = self.repositories.lock().unwrap();
+            match repositories.get(root) {
+                Some(dirty) => dirty.clone(),
+                None => {
+                    let dirty = Arc::new(Self::load(root)?);
+                    repositories.insert(root.to_owned(), dirty.clone());
+                    dirty
+                }
+            }
+        };
+
+        Ok(dirty.contains(&path))
+    }
+
+    fn load(root: &Path) -> Result<HashSet<PathBuf>> {
+        // Untracked files can't be restored by git either, so they
+        // count as dirty.
+        let stdout = git_in(
+            root,
+            ["status", "--porcelain", "-z", "--untracked-files=all"],
+        )?;
+        let mut entries = stdout.split(|&b| b == b'\0').filter(|it| !it.is_empty());
+
+        // Each entry is `XY path`, where renames and copies are
+        // followed by a separate entry with the original path.
+        let mut dirty = HashSet::new();
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+
+            let path = String::from_utf8_lossy(&entry[3..]);
+            dirty.insert(root.join(path.as_ref()));
+
+            if matches!(entry[0], b'R' | b'C') {
+                entries.next();
+            }
+        }
+
+        Ok(dirty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_changed_lines() {
+        let diff = "\
+diff --git a a
+--- a
++++ a
+@@ -2 +2 @@ foo
+-foo
++bar
+@@ -4,0 +5,3 @@ foo
++bar
++bar
++bar
+@@ -10,2 +12,0 @@ foo
+-foo
+-foo
+diff --git b b
+--- b
++++ /dev/null
+@@ -1 +0,0 @@
+-foo
+";
+        let changed_lines = ChangedLines::parse(Path::new("/root"), diff);
+
+        assert_eq!(changed_lines.files.len(), 1);
+        assert_eq!(
+            changed_lines.files[Path::new("/root/a")],
+            vec![2..=2, 5..=7]
+        );
+    }
+
+    #[test]
+    fn test_parse_changed_lines_in_hunk_like_header() {
+        // The added line "++ b" looks like the header of a file b.
+        let diff = "\
+diff --git a a
+--- a
++++ a
+@@ -1,0 +2,2 @@
++++ b
++foo
+@@ -5 +7 @@
+-foo
++bar
+";
+        let changed_lines = ChangedLines::parse(Path::new("/root"), diff);
+
+        assert_eq!(changed_lines.files.len(), 1);
+        assert_eq!(
+            changed_lines.files[Path::new("/root/a")],
+            vec![2..=3, 7..=7]
+        );
+    }
+
+    #[test]
+    fn test_parse_changed_lines_quoted_paths() {
+        let diff = "\
+diff --git \"a\\\"b\" \"a\\\"b\"
+--- \"a\\\"b\"
++++ \"a\\\"b\"
+@@ -1 +1 @@
+-foo
++bar
+diff --git a b a b
+--- a b\t
++++ a b\t
+@@ -1 +1 @@
+-foo
++bar
+diff --git \"\\303\\251\\tc\" \"\\303\\251\\tc\"
+--- \"\\303\\251\\tc\"
++++ \"\\303\\251\\tc\"
+@@ -1 +1 @@
+-foo
++bar
+";
+        let changed_lines = ChangedLines::parse(Path::new("/root"), diff);
+
+        let mut paths: Vec<_> = changed_lines.files.keys().cloned().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/root/a b"),
+                PathBuf::from("/root/a\"b"),
+                PathBuf::from("/root/\u{e9}\tc"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,2069 @@
+//! Recursively find and replace. Like sed, but memorable.
+//!
+//! This is the library behind the `fnr` command line tool. A run is
+//! configured with [`FindAndReplace`]:
+//!
+//! ```no_run
+//! use fnr::{Decision, Event, FindAndReplace, Proposal};
+//!
+//! let stats = FindAndReplace::new(r"old_(\w+)", "new_$1")
+//!     .path("src")
+//!     .decide_with(|proposal| match proposal {
+//!         Proposal::Replace { search_match, .. } if search_match.line.1.contains("keep") => {
+//!             Decision::Ignore
+//!         }
+//!         _ => Decision::Accept,
+//!     })
+//!     .events(|event: Event<'_>| {
+//!         if let Event::Replaced { path, num_replaced } = event {
+//!             println!("{}: {} replaced", path.display(), num_replaced);
+//!         }
+//!         Ok(())
+//!     })
+//!     .run()?;
+//!
+//! println!("{} of {} matches replaced", stats.num_replacements(), stats.num_matches());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+// Kept in the style the code was first written in, predating these
+// lints.
+#![allow(mismatched_lifetime_syntaxes, clippy::unnecessary_map_or)]
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use grep::regex::{RegexMatcher, RegexMatcherBuilder};
+use grep::searcher::{BinaryDetection, SearcherBuilder};
+use ignore::{DirEntry, WalkBuilder, WalkState};
+use regex::RegexSet;
+use termcolor::{Buffer, BufferWriter, StandardStream};
+
+mod editor;
+mod event;
+mod git;
+mod matcher;
+mod ordered;
+mod printer;
+mod rename;
+mod replace;
+mod scope;
+mod search;
+mod structural;
+#[cfg(feature = "tree-sitter")]
+mod tree_query;
+mod tui;
+
+use crate::git::{ChangedLines, DirtyFiles};
+use crate::matcher::PatternMatcher;
+use crate::printer::MatchPrinterBuilder;
+use crate::rename::PathRenamer;
+use crate::replace::{
+    DecisionCallback, LineEdits, ReplacementDecider, ReplacementDecision, ReplacementTemplate,
+    Replacer, ReplacerFactory, Swap,
+};
+use crate::scope::ScopeFilter;
+use crate::search::{RegexSearcherFactory, Regions, Searcher, SearcherFactory};
+use crate::structural::StructuralMatcher;
+#[cfg(feature = "tree-sitter")]
+use crate::tree_query::TreeQuery;
+use crate::tui::ReviewFile;
+
+pub use crate::event::{Decision, Event, EventSink, Proposal};
+pub use crate::printer::MatchPrintMode;
+pub use crate::replace::PromptInput;
+pub use crate::scope::Scope;
+pub use crate::search::{Line, Match};
+pub use termcolor::ColorChoice;
+
+/// Counts of what happened during a run.
+#[derive(Debug)]
+pub struct Statistics {
+    wall_time_ns: AtomicU64,
+    search_time_ns: AtomicU64,
+    files_total: AtomicUsize,
+    files_searched: AtomicUsize,
+    files_ignored: AtomicUsize,
+    files_with_matches: AtomicUsize,
+    files_with_replacements: AtomicUsize,
+    files_dirty: AtomicUsize,
+    num_matches: AtomicUsize,
+    num_changes: AtomicUsize,
+    num_replacements: AtomicUsize,
+    num_path_matches: AtomicUsize,
+    num_renames: AtomicUsize,
+    num_errors: AtomicUsize,
+
+    /// Matches still allowed by `--max-total`, shared between threads.
+    match_budget: AtomicUsize,
+}
+
+struct StatSearchTimer<'a> {
+    started_at: Instant,
+    stats: &'a Statistics,
+}
+
+impl<'a> Drop for StatSearchTimer<'a> {
+    fn drop(&mut self) {
+        let elapsed = self.started_at.elapsed();
+        self.stats.add_elapsed_search_time(elapsed);
+    }
+}
+
+impl Statistics {
+    fn new(max_total: Option<usize>) -> Statistics {
+        Statistics {
+            wall_time_ns: 0.into(),
+            search_time_ns: 0.into(),
+            files_total: 0.into(),
+            files_searched: 0.into(),
+            files_ignored: 0.into(),
+            files_with_matches: 0.into(),
+            files_with_replacements: 0.into(),
+            files_dirty: 0.into(),
+
+            num_matches: 0.into(),
+            num_changes: 0.into(),
+            num_replacements: 0.into(),
+            num_path_matches: 0.into(),
+            num_renames: 0.into(),
+            num_errors: 0.into(),
+
+            match_budget: max_total.unwrap_or(usize::MAX).into(),
+        }
+    }
+
+    pub fn num_matches(&self) -> usize {
+        self.num_matches.load(Ordering::Relaxed)
+    }
+
+    /// Matches whose replacement differs from the original, whether
+    /// or not it was written.
+    pub fn num_changes(&self) -> usize {
+        self.num_changes.load(Ordering::Relaxed)
+    }
+
+    pub fn num_replacements(&self) -> usize {
+        self.num_replacements.load(Ordering::Relaxed)
+    }
+
+    pub fn num_path_matches(&self) -> usize {
+        self.num_path_matches.load(Ordering::Relaxed)
+    }
+
+    pub fn num_renames(&self) -> usize {
+        self.num_renames.load(Ordering::Relaxed)
+    }
+
+    /// Errors which were reported without stopping the run.
+    pub fn num_errors(&self) -> usize {
+        self.num_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn files_searched(&self) -> usize {
+        self.files_searched.load(Ordering::Relaxed)
+    }
+
+    pub fn files_with_matches(&self) -> usize {
+        self.files_with_matches.load(Ordering::Relaxed)
+    }
+
+    pub fn files_with_replacements(&self) -> usize {
+        self.files_with_replacements.load(Ordering::Relaxed)
+    }
+
+    pub fn files_dirty(&self) -> usize {
+        self.files_dirty.load(Ordering::Relaxed)
+    }
+
+    fn search_timer(&self) -> StatSearchTimer {
+        StatSearchTimer {
+            stats: self,
+            started_at: Instant::now(),
+        }
+    }
+
+    #[inline]
+    fn add_elapsed_wall_time(&self, d: Duration) {
+        self.wall_time_ns
+            .fetch_add(d.as_nanos().try_into().unwrap_or(0), Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_elapsed_search_time(&self, d: Duration) {
+        self.search_time_ns
+            .fetch_add(d.as_nanos().try_into().unwrap_or(0), Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn visit_file(&self, searched: bool) {
+        self.files_total.fetch_add(1, Ordering::Relaxed);
+        if searched {
+            self.files_searched.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.files_ignored.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn remaining_budget(&self) -> usize {
+        self.match_budget.load(Ordering::Relaxed)
+    }
+
+    /// Take up to `num_matches` from the budget, returning how many
+    /// were granted.
+    fn reserve_matches(&self, num_matches: usize) -> usize {
+        let prev = self
+            .match_budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                Some(remaining.saturating_sub(num_matches))
+            })
+            .unwrap();
+
+        prev.min(num_matches)
+    }
+
+    #[inline]
+    fn add_matches(&self, num_matches: usize) {
+        self.files_with_matches.fetch_add(1, Ordering::Relaxed);
+        self.num_matches.fetch_add(num_matches, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_changes(&self, num_changes: usize) {
+        self.num_changes.fetch_add(num_changes, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_replacements(&self, num_replacements: usize) {
+        self.files_with_replacements.fetch_add(1, Ordering::Relaxed);
+        self.num_replacements
+            .fetch_add(num_replacements, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn skip_dirty_file(&self) {
+        self.files_dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_path_match(&self) {
+        self.num_path_matches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_renames(&self, num_renames: usize) {
+        self.num_renames.fetch_add(num_renames, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn add_error(&self) {
+        self.num_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\
+wall time               {wall_time_secs} s
+search time             {search_time_secs} s
+num matches             {num_matches:?}
+num replacements        {num_replacements:?}
+num path matches        {num_path_matches:?}
+num renames             {num_renames:?}
+num errors              {num_errors:?}
+total files             {files_total:?}
+  ... ignored           {files_ignored:?}
+  ... searched          {files_searched:?}
+  ... with matches      {files_with_matches:?}
+  ... with replacements {files_with_replacements:?}
+  ... skipped as dirty  {files_dirty:?}",
+            wall_time_secs =
+                Duration::from_nanos(self.wall_time_ns.load(Ordering::Relaxed)).as_secs_f32(),
+            search_time_secs =
+                Duration::from_nanos(self.search_time_ns.load(Ordering::Relaxed)).as_secs_f32(),
+            num_matches = self.num_matches,
+            num_replacements = self.num_replacements,
+            num_path_matches = self.num_path_matches,
+            num_renames = self.num_renames,
+            num_errors = self.num_errors,
+            files_total = self.files_total,
+            files_ignored = self.files_ignored,
+            files_searched = self.files_searched,
+            files_with_matches = self.files_with_matches,
+            files_with_replacements = self.files_with_replacements,
+            files_dirty = self.files_dirty,
+        )
+    }
+}
+
+/// How FIND is matched against upper and lower case letters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Case {
+    Sensitive,
+    Insensitive,
+    /// Sensitive if FIND has uppercase characters, insensitive
+    /// otherwise.
+    Smart,
+}
+
+/// The order in which files are processed and printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortPreference {
+    Path,
+    /// Least recently modified first, across every directory.
+    Modified,
+    /// Whichever order files are found in, which may change between
+    /// runs when searching in parallel.
+    None,
+}
+
+/// What to do when writing to a file with uncommitted changes in git.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirtyPreference {
+    Skip,
+    Abort,
+    Allow,
+}
+
+/// How replacements are reviewed before they're written.
+enum Review {
+    Constantly(ReplacementDecision),
+    Prompt(PromptInput),
+    Tui,
+    Callback(Arc<Mutex<Box<DecisionCallback>>>),
+}
+
+enum Find {
+    /// Matches of `find` are left as they are without `replace`.
+    Pattern {
+        find: String,
+        replace: Option<String>,
+    },
+    Swap(Swap),
+}
+
+/// Builds and runs a find and replace over a set of paths.
+///
+/// Nothing is written unless [`write`](Self::write), a prompt, or a
+/// decision callback is chosen.
+pub struct FindAndReplace {
+    options: Options,
+    events: Option<Box<dyn EventSink>>,
+}
+
+struct Options {
+    find: Find,
+    search_only: bool,
+    paths: Vec<PathBuf>,
+    literal: bool,
+    structural: bool,
+    ts_query: bool,
+    case: Case,
+    word: bool,
+    include: Option<Vec<String>>,
+    exclude: Vec<String>,
+    hidden: bool,
+    all_files: bool,
+    before_context: usize,
+    after_context: usize,
+    rename_paths: bool,
+    git_mv: bool,
+    changed_since: Option<String>,
+    staged: bool,
+    only_changed_lines: Option<String>,
+    dirty: DirtyPreference,
+    sort: SortPreference,
+    max_count: Option<usize>,
+    max_total: Option<usize>,
+    occurrence: Option<usize>,
+    lines: Option<RangeInclusive<u64>>,
+    between: Option<(String, String)>,
+    scope: Option<Scope>,
+    delete_lines: bool,
+    insert_before: Option<String>,
+    insert_after: Option<String>,
+    review: Review,
+    print_mode: MatchPrintMode,
+    color: ColorChoice,
+    print_stats: bool,
+}
+
+const DEFAULT_CONTEXT_LINES: usize = 2;
+
+/// How many files may be searched ahead of the one being reviewed.
+const SEARCH_AHEAD_FILES: usize = 64;
+
+impl FindAndReplace {
+    /// Replace matches of the regular expression `find` with
+    /// `replace`, which may reference capture groups as `$1`, `$2`,
+    /// etc.
+    pub fn new(find: impl Into<String>, replace: impl Into<String>) -> FindAndReplace {
+        Self::with_find(Find::Pattern {
+            find: find.into(),
+            replace: Some(replace.into()),
+        })
+    }
+
+    /// Exchange occurrences of `left` and `right` with each other.
+    /// Both are matched literally.
+    pub fn swap(left: impl Into<String>, right: impl Into<String>) -> FindAndReplace {
+        Self::with_find(Find::Swap(Swap {
+            left: left.into(),
+            right: right.into(),
+        }))
+    }
+
+    /// Only search for matches of the regular expression `find`.
+    /// Matches are printed without replacements, and nothing is ever
+    /// written, whichever review is chosen.
+    pub fn search(find: impl Into<String>) -> FindAndReplace {
+        let mut find_and_replace = Self::matching_lines(find);
+        find_and_replace.options.search_only = true;
+        find_and_replace
+    }
+
+    /// Leave matches of the regular expression `find` as they are, to
+    /// [delete](Self::delete_lines) their lines or insert lines
+    /// [before](Self::insert_before) or [after](Self::insert_after)
+    /// them.
+    pub fn matching_lines(find: impl Into<String>) -> FindAndReplace {
+        Self::with_find(Find::Pattern {
+            find: find.into(),
+            replace: None,
+        })
+    }
+
+    fn with_find(find: Find) -> FindAndReplace {
+        FindAndReplace {
+            options: Options {
+                find,
+                search_only: false,
+                paths: vec![],
+                literal: false,
+                structural: false,
+                ts_query: false,
+                case: Case::Smart,
+                word: false,
+                include: None,
+                exclude: vec![],
+                hidden: false,
+                all_files: false,
+                before_context: DEFAULT_CONTEXT_LINES,
+                after_context: DEFAULT_CONTEXT_LINES,
+                rename_paths: false,
+                git_mv: false,
+                changed_since: None,
+                staged: false,
+                only_changed_lines: None,
+                dirty: DirtyPreference::Skip,
+                sort: SortPreference::None,
+                max_count: None,
+                max_total: None,
+                occurrence: None,
+                lines: None,
+                between: None,
+                scope: None,
+                delete_lines: false,
+                insert_before: None,
+                insert_after: None,
+                review: Review::Constantly(ReplacementDecision::Ignore),
+                print_mode: MatchPrintMode::Full,
+                color: ColorChoice::Auto,
+                print_stats: false,
+            },
+            events: None,
+        }
+    }
+
+    /// Add a file or directory to search. The current directory is
+    /// searched if none are given.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.paths.push(path.into());
+        self
+    }
+
+    pub fn paths<P: Into<PathBuf>>(mut self, paths: impl IntoIterator<Item = P>) -> Self {
+        self.options.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Treat the pattern as a string rather than a regular expression.
+    pub fn literal(mut self, literal: bool) -> Self {
+        self.options.literal = literal;
+        self
+    }
+
+    /// Treat the pattern as a structural pattern, where holes like
+    /// `:[name]` match text with balanced brackets, possibly across
+    /// lines. Holes are referred to in the replacement the same way.
+    ///
+    /// Structural patterns always match case sensitively, so this
+    /// can't be combined with [`Case::Insensitive`].
+    pub fn structural(mut self, structural: bool) -> Self {
+        self.options.structural = structural;
+        self
+    }
+
+    /// Treat the pattern as a tree-sitter query, replacing the text
+    /// spanned by each match's captured nodes. The replacement refers
+    /// to a node captured as `@name` by `$name` or `${name}`.
+    ///
+    /// Only Rust and TypeScript files are searched. Options which
+    /// work line by line, such as [`lines`](Self::lines) or
+    /// [`scope`](Self::scope), are rejected. Needs the `tree-sitter`
+    /// feature, which is on by default.
+    pub fn ts_query(mut self, ts_query: bool) -> Self {
+        self.options.ts_query = ts_query;
+        self
+    }
+
+    pub fn case(mut self, case: Case) -> Self {
+        self.options.case = case;
+        self
+    }
+
+    /// Only match at word boundaries.
+    pub fn word(mut self, word: bool) -> Self {
+        self.options.word = word;
+        self
+    }
+
+    /// Only search paths containing `pattern`. Takes precedence over
+    /// [`exclude`](Self::exclude).
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.options
+            .include
+            .get_or_insert_with(Vec::new)
+            .push(pattern.into());
+        self
+    }
+
+    /// Skip paths containing `pattern`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.options.exclude.push(pattern.into());
+        self
+    }
+
+    /// Search hidden files and directories.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.options.hidden = hidden;
+        self
+    }
+
+    /// Search all files, including hidden and ignored ones.
+    pub fn all_files(mut self, all_files: bool) -> Self {
+        self.options.all_files = all_files;
+        self
+    }
+
+    /// Lines of context to include before and after each match.
+    pub fn context(mut self, before: usize, after: usize) -> Self {
+        self.options.before_context = before;
+        self.options.after_context = after;
+        self
+    }
+
+    /// Also rename files and directories whose names match.
+    pub fn rename_paths(mut self, rename_paths: bool) -> Self {
+        self.options.rename_paths = rename_paths;
+        self
+    }
+
+    /// Use `git mv` when renaming paths.
+    pub fn git_mv(mut self, git_mv: bool) -> Self {
+        self.options.git_mv = git_mv;
+        self
+    }
+
+    /// Only search files changed relative to the git revision `rev`,
+    /// including uncommitted changes and untracked files.
+    pub fn changed_since(mut self, rev: impl Into<String>) -> Self {
+        self.options.changed_since = Some(rev.into());
+        self
+    }
+
+    /// Only search files with changes staged in git.
+    pub fn staged(mut self, staged: bool) -> Self {
+        self.options.staged = staged;
+        self
+    }
+
+    /// Only replace matches on lines added or modified relative to
+    /// the git revision `rev`.
+    pub fn only_changed_lines(mut self, rev: impl Into<String>) -> Self {
+        self.options.only_changed_lines = Some(rev.into());
+        self
+    }
+
+    /// What to do with files that have uncommitted changes in git,
+    /// or are untracked, when they may be written to.
+    ///
+    /// Files are always allowed to be written when only searching
+    /// changed files, as they're dirty by definition.
+    pub fn dirty(mut self, dirty: DirtyPreference) -> Self {
+        self.options.dirty = dirty;
+        self
+    }
+
+    pub fn sort(mut self, sort: SortPreference) -> Self {
+        self.options.sort = sort;
+        self
+    }
+
+    /// Only consider the first `max_count` matching lines of each
+    /// file.
+    pub fn max_count(mut self, max_count: usize) -> Self {
+        self.options.max_count = Some(max_count);
+        self
+    }
+
+    /// Only replace the `occurrence`th match on each line, counting
+    /// from 1. Lines with fewer matches are left alone.
+    pub fn occurrence(mut self, occurrence: usize) -> Self {
+        self.options.occurrence = Some(occurrence);
+        self
+    }
+
+    /// Only replace the first match in each file.
+    pub fn first_in_file(self) -> Self {
+        self.max_count(1).occurrence(1)
+    }
+
+    /// Only replace matches on these lines, counting from 1.
+    pub fn lines(mut self, lines: RangeInclusive<u64>) -> Self {
+        self.options.lines = Some(lines);
+        self
+    }
+
+    /// Only replace matches between lines matching the regular
+    /// expressions `start` and `end`, e.g. in a generated section.
+    pub fn between(mut self, start: impl Into<String>, end: impl Into<String>) -> Self {
+        self.options.between = Some((start.into(), end.into()));
+        self
+    }
+
+    /// Only replace matches in code, comments or string literals.
+    /// Files in languages which aren't recognized by their extension
+    /// are skipped.
+    pub fn scope(mut self, scope: Scope) -> Self {
+        self.options.scope = Some(scope);
+        self
+    }
+
+    /// Delete the lines matches are on, rather than replacing within
+    /// them.
+    pub fn delete_lines(mut self, delete_lines: bool) -> Self {
+        self.options.delete_lines = delete_lines;
+        self
+    }
+
+    /// Insert a line before each matching line. The line may reference
+    /// capture groups of the line's first match, like the replacement.
+    pub fn insert_before(mut self, template: impl Into<String>) -> Self {
+        self.options.insert_before = Some(template.into());
+        self
+    }
+
+    /// Insert a line after each matching line, as with
+    /// [`insert_before`](Self::insert_before).
+    pub fn insert_after(mut self, template: impl Into<String>) -> Self {
+        self.options.insert_after = Some(template.into());
+        self
+    }
+
+    /// Only consider `max_total` matching lines across all files.
+    /// Which files these come from isn't deterministic unless files
+    /// are sorted.
+    pub fn max_total(mut self, max_total: usize) -> Self {
+        self.options.max_total = Some(max_total);
+        self
+    }
+
+    /// Write every replacement without asking.
+    pub fn write(mut self) -> Self {
+        self.options.review = Review::Constantly(ReplacementDecision::Accept);
+        self
+    }
+
+    /// Show replacements without writing any of them. This is the
+    /// default.
+    pub fn dry_run(mut self) -> Self {
+        self.options.review = Review::Constantly(ReplacementDecision::Ignore);
+        self
+    }
+
+    /// Ask about each replacement at an interactive prompt.
+    pub fn prompt(mut self, input: PromptInput) -> Self {
+        self.options.review = Review::Prompt(input);
+        self
+    }
+
+    /// Review all replacements in a full screen terminal interface.
+    pub fn tui(mut self) -> Self {
+        self.options.review = Review::Tui;
+        self
+    }
+
+    /// Decide on each replacement and rename with `callback`, in place
+    /// of the interactive prompt. Files are processed one at a time in
+    /// a stable order.
+    pub fn decide_with<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(Proposal<'_>) -> Decision + Send + 'static,
+    {
+        self.options.review = Review::Callback(Arc::new(Mutex::new(Box::new(callback))));
+        self
+    }
+
+    /// Send events to `events` rather than printing them. Files are
+    /// processed one at a time, in the order given by `sort`.
+    pub fn events(mut self, events: impl EventSink + 'static) -> Self {
+        self.events = Some(Box::new(events));
+        self
+    }
+
+    /// How matches are printed to standard output.
+    pub fn print_mode(mut self, print_mode: MatchPrintMode) -> Self {
+        self.options.print_mode = print_mode;
+        self
+    }
+
+    pub fn color(mut self, color: ColorChoice) -> Self {
+        self.options.color = color;
+        self
+    }
+
+    /// Print debug statistics once finished.
+    pub fn print_stats(mut self, print_stats: bool) -> Self {
+        self.options.print_stats = print_stats;
+        self
+    }
+
+    /// Replace matches in `input` a line at a time, writing the result
+    /// to `output` without printing or reviewing anything.
+    ///
+    /// Only one line is held in memory at once, so matches can't span
+    /// lines, and options which work on whole files or paths are
+    /// rejected.
+    pub fn filter(self, input: impl io::BufRead, output: impl Write) -> Result<Statistics> {
+        let options = self.options;
+        ensure!(
+            !options.ts_query && !options.spans_lines(),
+            "matches spanning lines can't be replaced in a stream"
+        );
+        if let Some(name) = options.unsupported_by_filter() {
+            bail!("{} can't be used with a stream", name);
+        }
+
+        let replacer = ReplacerFactory::new(
+            Some(Arc::new(options.pattern_matcher()?)),
+            options.replacement_template(),
+            ReplacementDecider::constantly(ReplacementDecision::Accept),
+            options.occurrence,
+            options.line_edits(),
+        )
+        .build();
+
+        let stats = Statistics::new(None);
+        let num_changed = replacer.filter(input, output)?;
+        if num_changed > 0 {
+            stats.add_matches(num_changed);
+            stats.add_replacements(num_changed);
+            stats.add_changes(num_changed);
+        }
+
+        Ok(stats)
+    }
+
+    pub fn run(mut self) -> Result<Statistics> {
+        if self.options.search_only {
+            self.options.review = Review::Constantly(ReplacementDecision::Ignore);
+        }
+
+        let mut find_and_replacer = FindAndReplacer::from_options(self.options)?;
+        find_and_replacer.run(self.events)
+    }
+}
+
+impl Options {
+    fn path_matcher(&self) -> Result<PathMatcher> {
+        let included_paths = self.include.as_ref().map(|included_paths| {
+            let escaped = included_paths.iter().map(|p| regex::escape(p));
+            RegexSet::new(escaped).unwrap()
+        });
+
+        let excluded_paths = {
+            let escaped = self.exclude.iter().map(|p| regex::escape(p));
+            RegexSet::new(escaped)?
+        };
+
+        Ok(PathMatcher {
+            included_paths,
+            excluded_paths,
+        })
+    }
+
+    fn pattern(&self) -> Cow<str> {
+        match &self.find {
+            Find::Swap(swap) => swap.pattern().into(),
+            Find::Pattern { find, .. } if self.literal => regex::escape(find).into(),
+            Find::Pattern { find, .. } => find.into(),
+        }
+    }
+
+    fn replacement_template(&self) -> ReplacementTemplate {
+        match &self.find {
+            Find::Swap(swap) => ReplacementTemplate::Swap(Arc::new(swap.clone())),
+            Find::Pattern { replace: None, .. } => ReplacementTemplate::Unchanged,
+            Find::Pattern {
+                replace: Some(replace),
+                ..
+            } => ReplacementTemplate::Captures(self.template(replace)),
+        }
+    }
+
+    /// A template referring to captures as the pattern names them.
+    fn template(&self, template: &str) -> Arc<String> {
+        if self.structural {
+            Arc::new(structural::replacement_template(template))
+        } else {
+            Arc::new(template.to_owned())
+        }
+    }
+
+    fn line_edits(&self) -> LineEdits {
+        LineEdits {
+            delete: self.delete_lines,
+            insert_before: self.insert_before.as_deref().map(|t| self.template(t)),
+            insert_after: self.insert_after.as_deref().map(|t| self.template(t)),
+        }
+    }
+
+    fn pattern_matcher(&self) -> Result<PatternMatcher> {
+        if let Find::Pattern { find, .. } = &self.find {
+            if self.structural {
+                let matcher = StructuralMatcher::new(find)
+                    .with_context(|| format!("Failed to parse structural pattern '{}'", find))?;
+                return Ok(PatternMatcher::Structural(matcher));
+            }
+        }
+
+        self.regex_matcher().map(PatternMatcher::Regex)
+    }
+
+    #[cfg(feature = "tree-sitter")]
+    fn tree_query(&self) -> Result<TreeQuery> {
+        let (find, replace) = match &self.find {
+            Find::Pattern { find, replace } => (find, replace.as_deref().unwrap_or_default()),
+            Find::Swap(_) => bail!("tree-sitter queries can't swap"),
+        };
+
+        TreeQuery::new(find, replace, self.before_context, self.after_context)
+            .with_context(|| format!("Failed to parse query '{}'", find))
+    }
+
+    /// Options which only make sense with a line-oriented pattern,
+    /// named as they're set.
+    fn unsupported_by_tree_query(&self) -> Option<&'static str> {
+        [
+            ("structural", self.structural),
+            ("lines", self.lines.is_some()),
+            ("between", self.between.is_some()),
+            ("occurrence", self.occurrence.is_some()),
+            ("scope", self.scope.is_some()),
+            ("rename_paths", self.rename_paths),
+            ("insert_before", self.insert_before.is_some()),
+            ("insert_after", self.insert_after.is_some()),
+        ]
+        .into_iter()
+        .find_map(|(name, is_set)| is_set.then_some(name))
+    }
+
+    /// Options which pick or order files, or which need more than one
+    /// line at a time, named as they're set.
+    fn unsupported_by_filter(&self) -> Option<&'static str> {
+        [
+            ("structural", self.structural),
+            ("include", self.include.is_some()),
+            ("exclude", !self.exclude.is_empty()),
+            ("hidden", self.hidden),
+            ("all_files", self.all_files),
+            ("rename_paths", self.rename_paths),
+            ("changed_since", self.changed_since.is_some()),
+            ("staged", self.staged),
+            ("only_changed_lines", self.only_changed_lines.is_some()),
+            ("dirty", self.dirty != DirtyPreference::Skip),
+            ("sort", self.sort != SortPreference::None),
+            ("max_count", self.max_count.is_some()),
+            ("max_total", self.max_total.is_some()),
+            ("lines", self.lines.is_some()),
+            ("between", self.between.is_some()),
+            ("scope", self.scope.is_some()),
+        ]
+        .into_iter()
+        .find_map(|(name, is_set)| is_set.then_some(name))
+    }
+
+    /// Whether FIND itself spans lines, as when read from a file.
+    fn spans_lines(&self) -> bool {
+        matches!(&self.find, Find::Pattern { find, .. } if find.contains('\n'))
+    }
+
+    fn regex_matcher(&self) -> Result<RegexMatcher> {
+        let pattern = self.pattern();
+
+        RegexMatcherBuilder::new()
+            .case_insensitive(self.case == Case::Insensitive)
+            .case_smart(self.case == Case::Smart)
+            .word(self.word)
+            .build(&pattern)
+            .with_context(|| format!("Failed to parse pattern '{}'", pattern))
+    }
+
+    /// Paths to search, defaulting to the current directory.
+    fn given_paths(&self) -> Cow<'_, [PathBuf]> {
+        if self.paths.is_empty() {
+            Cow::from(vec![PathBuf::from(".")])
+        } else {
+            Cow::from(&self.paths)
+        }
+    }
+
+    /// Files changed in git, when only those should be searched.
+    fn changed_files(&self) -> Result<Option<HashSet<PathBuf>>> {
+        let rev = self
+            .changed_since
+            .as_deref()
+            .or(self.only_changed_lines.as_deref());
+
+        if rev.is_none() && !self.staged {
+            return Ok(None);
+        }
+
+        Ok(Some(git::changed_files(
+            rev,
+            self.staged,
+            &self.given_paths(),
+        )?))
+    }
+
+    fn changed_lines(&self) -> Result<Option<ChangedLines>> {
+        match &self.only_changed_lines {
+            Some(rev) => Ok(Some(ChangedLines::load(rev, &self.given_paths())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn num_threads(&self) -> usize {
+        // This is copied over from ripgrep, and seems to work well.
+        std::cmp::min(12, num_cpus::get())
+    }
+
+    /// Walks the paths to search, or `None` if there are none, such
+    /// as when no files have changed.
+    fn file_walker(&self) -> Result<Option<WalkBuilder>> {
+        // Files without changed lines can't have any matches, so don't
+        // bother searching them. Changed files are still walked as
+        // usual, so hidden and ignored ones stay skipped.
+        let changed_files = self.changed_files()?;
+        let is_searched = |path: &Path| match &changed_files {
+            Some(changed_files) => is_changed(changed_files, path),
+            None => true,
+        };
+
+        let paths: Vec<_> = self
+            .given_paths()
+            .iter()
+            .filter(|path| path.is_dir() || is_searched(path))
+            .cloned()
+            .collect();
+        if paths.is_empty() || changed_files.as_ref().is_some_and(|it| it.is_empty()) {
+            return Ok(None);
+        }
+
+        let mut file_walker = WalkBuilder::new(&paths[0]);
+        for path in &paths[1..] {
+            file_walker.add(path);
+        }
+
+        if let Some(changed_files) = changed_files {
+            file_walker.filter_entry(move |entry| {
+                entry.file_type().is_some_and(|it| it.is_dir())
+                    || is_changed(&changed_files, entry.path())
+            });
+        }
+
+        file_walker.threads(self.num_threads());
+
+        // Files are sorted by modified time once they've all been
+        // walked, see `FindAndReplacer::walk_in_order`.
+        if self.sort == SortPreference::Path {
+            file_walker.sort_by_file_name(|a, b| a.cmp(b));
+        }
+
+        let should_ignore = !self.all_files;
+        let should_show_hidden = self.hidden || self.all_files;
+        file_walker
+            .hidden(!should_show_hidden)
+            .ignore(should_ignore)
+            .git_ignore(should_ignore)
+            .git_exclude(should_ignore)
+            .parents(should_ignore);
+
+        Ok(Some(file_walker))
+    }
+
+    fn replacement_decider(&self) -> ReplacementDecider {
+        match &self.review {
+            Review::Constantly(decision) => ReplacementDecider::constantly(*decision),
+            Review::Prompt(input) => ReplacementDecider::with_prompt(*input),
+            // Decisions are made in the review itself.
+            Review::Tui => ReplacementDecider::constantly(ReplacementDecision::Ignore),
+            Review::Callback(callback) => ReplacementDecider::Callback(callback.clone()),
+        }
+    }
+
+    fn path_renamer(&self) -> Option<PathRenamer> {
+        if !self.rename_paths {
+            None
+        } else if self.git_mv {
+            Some(PathRenamer::Git)
+        } else {
+            Some(PathRenamer::Filesystem)
+        }
+    }
+
+    fn dirty_file_guard(&self) -> Option<DirtyFileGuard> {
+        let writes = !matches!(self.review, Review::Constantly(ReplacementDecision::Ignore));
+        let searches_changes =
+            self.changed_since.is_some() || self.staged || self.only_changed_lines.is_some();
+
+        match self.dirty {
+            DirtyPreference::Allow => None,
+            _ if !writes || searches_changes => None,
+            preference => Some(DirtyFileGuard {
+                preference,
+                dirty_files: DirtyFiles::default(),
+            }),
+        }
+    }
+
+    fn regions(&self) -> Result<Regions> {
+        let mut regions = Regions::default();
+        if let Some(lines) = &self.lines {
+            regions = regions.lines(lines.clone());
+        }
+        if let Some((start, end)) = &self.between {
+            let marker = |pattern: &str| {
+                regex::bytes::Regex::new(pattern)
+                    .with_context(|| format!("Failed to parse marker pattern '{}'", pattern))
+            };
+            regions = regions.between(marker(start)?, marker(end)?);
+        }
+
+        Ok(regions)
+    }
+
+    fn searcher_builder(&self) -> SearcherBuilder {
+        let mut searcher_builder = SearcherBuilder::new();
+        searcher_builder
+            .binary_detection(BinaryDetection::quit(0x00))
+            .line_number(true)
+            .before_context(self.before_context)
+            .after_context(self.after_context);
+
+        searcher_builder
+    }
+
+    fn match_printer(&self) -> MatchPrinterBuilder {
+        MatchPrinterBuilder {
+            print_mode: self.print_mode,
+            search_only: self.search_only,
+            writes_enabled: !matches!(self.review, Review::Constantly(ReplacementDecision::Ignore)),
+        }
+    }
+}
+
+struct FindAndReplacer {
+    options: Options,
+    file_walker: Option<WalkBuilder>,
+    path_matcher: PathMatcher,
+    path_renamer: Option<PathRenamer>,
+    changed_lines: Option<ChangedLines>,
+    scope_filter: Option<ScopeFilter>,
+    dirty_file_guard: Option<DirtyFileGuard>,
+    match_printer: MatchPrinterBuilder,
+    replacer_factory: ReplacerFactory,
+    searcher_factory: SearcherFactory,
+}
+
+impl FindAndReplacer {
+    fn from_options(options: Options) -> Result<FindAndReplacer> {
+        ensure!(
+            options.occurrence != Some(0),
+            "occurrences are counted from 1"
+        );
+        ensure!(
+            !(options.structural && options.case == Case::Insensitive),
+            "structural patterns always match case sensitively"
+        );
+
+        if options.ts_query {
+            if let Some(name) = options.unsupported_by_tree_query() {
+                bail!("{} can't be used with a tree-sitter query", name);
+            }
+
+            #[cfg(feature = "tree-sitter")]
+            return Self::with_searcher(
+                SearcherFactory::TreeQuery(Arc::new(options.tree_query()?)),
+                None,
+                options,
+            );
+            #[cfg(not(feature = "tree-sitter"))]
+            bail!("tree-sitter queries aren't supported by this build of fnr");
+        }
+
+        let pattern_matcher = Arc::new(options.pattern_matcher()?);
+
+        let mut searcher_builder = options.searcher_builder();
+        searcher_builder.multi_line(pattern_matcher.is_multi_line() || options.spans_lines());
+
+        let searcher_factory = RegexSearcherFactory::new(
+            searcher_builder,
+            pattern_matcher.clone(),
+            options.occurrence.unwrap_or(1),
+            options.regions()?,
+        )
+        .keep_contents(options.scope.is_some());
+
+        Self::with_searcher(
+            SearcherFactory::Pattern(searcher_factory),
+            Some(pattern_matcher),
+            options,
+        )
+    }
+
+    /// `pattern_matcher` is what the searcher matches, if it matches
+    /// text at all.
+    fn with_searcher(
+        searcher_factory: SearcherFactory,
+        pattern_matcher: Option<Arc<PatternMatcher>>,
+        options: Options,
+    ) -> Result<FindAndReplacer> {
+        // TODO: Confirm that template does not reference more capture groups than exist.
+        let replacer_factory = ReplacerFactory::new(
+            pattern_matcher.clone(),
+            options.replacement_template(),
+            options.replacement_decider(),
+            options.occurrence,
+            options.line_edits(),
+        );
+
+        let scope_filter = options
+            .scope
+            .zip(pattern_matcher)
+            .map(|(scope, pattern_matcher)| ScopeFilter::new(scope, pattern_matcher));
+
+        Ok(FindAndReplacer {
+            file_walker: options.file_walker()?,
+            path_matcher: options.path_matcher()?,
+            path_renamer: options.path_renamer(),
+            changed_lines: options.changed_lines()?,
+            scope_filter,
+            dirty_file_guard: options.dirty_file_guard(),
+            match_printer: options.match_printer(),
+            searcher_factory,
+            replacer_factory,
+
+            options,
+        })
+    }
+
+    fn run(&mut self, events: Option<Box<dyn EventSink>>) -> Result<Statistics> {
+        self.check_dirty_files()?;
+
+        // We can only use parallel mode if decisions and events can
+        // happen in any order.
+        match self.options.review {
+            Review::Tui => self.run_with_tui(),
+            Review::Prompt(_) | Review::Callback(_) => self.run_in_order(events),
+            Review::Constantly(_) if events.is_some() => self.run_in_order(events),
+            // Take from a shared budget in walk order, so that sorted
+            // runs pick the same matches every time.
+            Review::Constantly(_)
+                if self.options.max_total.is_some()
+                    && self.options.sort != SortPreference::None =>
+            {
+                self.run_in_order(events)
+            }
+            Review::Constantly(_) => self.run_parallel(),
+        }
+    }
+
+    /// With `--dirty=abort`, fail before anything is written if any
+    /// file with matches has uncommitted changes. Files are otherwise
+    /// written as soon as they're searched, so the run could stop
+    /// part way through.
+    fn check_dirty_files(&self) -> Result<()> {
+        let dirty_files = match &self.dirty_file_guard {
+            Some(guard) if guard.preference == DirtyPreference::Abort => &guard.dirty_files,
+            _ => return Ok(()),
+        };
+
+        // Errors are left to be reported by the run itself.
+        let stats = Statistics::new(None);
+        let mut searcher = self.searcher_factory.build();
+        for entry in self
+            .file_walker
+            .iter()
+            .flat_map(WalkBuilder::build)
+            .flatten()
+        {
+            let path = entry.path();
+            if !self.path_matcher.should_search(&entry)
+                || !dirty_files.is_dirty(path).unwrap_or(false)
+            {
+                continue;
+            }
+
+            let has_matches = self
+                .search_path(path, &mut searcher, &stats)
+                .is_ok_and(|matches| !matches.is_empty());
+            if has_matches {
+                return Err(
+                    anyhow!(DirtyFileGuard::ABORT_MESSAGE).context(format!("{}", path.display()))
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Search `path` for at most as many matches as could still be
+    /// used, so that the searcher can stop early.
+    fn search_path(
+        &self,
+        path: &Path,
+        searcher: &mut Searcher,
+        stats: &Statistics,
+    ) -> Result<Vec<Match>> {
+        // Lines are only filtered by change and scope after searching,
+        // so the limit can't be applied until then.
+        let max_matches = if self.changed_lines.is_some() || self.scope_filter.is_some() {
+            usize::MAX
+        } else {
+            let max_count = self.options.max_count.unwrap_or(usize::MAX);
+            max_count.min(stats.remaining_budget())
+        };
+
+        let mut matches = searcher.search_path(path, max_matches)?;
+        if let Some(changed_lines) = &self.changed_lines {
+            changed_lines.retain_changed(path, &mut matches);
+        }
+        if let Some(scope_filter) = &self.scope_filter {
+            let contents = searcher.contents().unwrap_or_default();
+            scope_filter.retain_in_scope(path, contents, &mut matches)?;
+        }
+
+        Ok(matches)
+    }
+
+    /// Drop matches beyond `--max-count`, and those not granted from
+    /// the `--max-total` budget.
+    fn limit_matches(&self, matches: &mut Vec<Match>, stats: &Statistics) {
+        if let Some(max_count) = self.options.max_count {
+            matches.truncate(max_count);
+        }
+
+        let num_granted = stats.reserve_matches(matches.len());
+        matches.truncate(num_granted);
+    }
+
+    /// Walk files serially in a stable order, passing each file to
+    /// search to `emit` until it returns `false`. Paths to rename and
+    /// errors are collected along the way, for the caller to report.
+    fn walk_in_order(
+        &self,
+        stats: &Statistics,
+        rename_candidates: &mut Vec<PathBuf>,
+        walk_errors: &Mutex<Vec<String>>,
+        emit: &mut dyn FnMut(PathBuf) -> bool,
+    ) {
+        if self.options.sort != SortPreference::Modified {
+            return self.walk(stats, rename_candidates, walk_errors, emit);
+        }
+
+        // The walk can only be sorted within each directory, so sort
+        // every file at once, looking up each one's time only once.
+        let mut paths = vec![];
+        self.walk(stats, rename_candidates, walk_errors, &mut |path| {
+            paths.push(path);
+            true
+        });
+        paths.sort_by_cached_key(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok();
+            (modified, path.clone())
+        });
+
+        for path in paths {
+            if !emit(path) {
+                break;
+            }
+        }
+    }
+
+    /// Walk files serially in the walker's order, as with
+    /// `walk_in_order`.
+    fn walk(
+        &self,
+        stats: &Statistics,
+        rename_candidates: &mut Vec<PathBuf>,
+        walk_errors: &Mutex<Vec<String>>,
+        emit: &mut dyn FnMut(PathBuf) -> bool,
+    ) {
+        let replacer = self.replacer_factory.build();
+
+        for dir_entry in self.file_walker.iter().flat_map(WalkBuilder::build) {
+            if let Ok(ref entry) = dir_entry {
+                if self.path_renamer.is_some()
+                    && self.path_matcher.path_matches(entry.path())
+                    && replacer.should_rename(entry.path())
+                {
+                    stats.add_path_match();
+                    rename_candidates.push(entry.path().to_owned());
+                }
+            }
+
+            let entry = match dir_entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    stats.add_error();
+                    walk_errors.lock().unwrap().push(err.to_string());
+                    continue;
+                }
+            };
+
+            if !self.path_matcher.should_search(&entry) {
+                stats.visit_file(false);
+                continue;
+            }
+
+            stats.visit_file(true);
+            if !emit(entry.into_path()) {
+                break;
+            }
+        }
+    }
+
+    fn run_in_order(&self, mut events: Option<Box<dyn EventSink>>) -> Result<Statistics> {
+        let stats = Statistics::new(self.options.max_total);
+        let start_time = Instant::now();
+
+        let mut replacer = self.replacer_factory.build();
+
+        let mut writer = StandardStream::stdout(self.options.color);
+        let mut match_printer = self.match_printer.build(&mut writer);
+        let sink: &mut dyn EventSink = match &mut events {
+            Some(events) => events.as_mut(),
+            None => &mut match_printer,
+        };
+
+        let mut rename_candidates = vec![];
+        let walk_errors = Mutex::new(vec![]);
+        let mut should_quit = false;
+        let mut abort_error = None;
+
+        // Files are searched on worker threads while the user answers
+        // prompts, but are still presented one at a time in walk order.
+        ordered::process_in_order(
+            self.options.num_threads(),
+            SEARCH_AHEAD_FILES,
+            |emit| self.walk_in_order(&stats, &mut rename_candidates, &walk_errors, emit),
+            || {
+                let mut searcher = self.searcher_factory.build();
+                let stats = &stats;
+
+                move |path: &Path| {
+                    let _search_timer = stats.search_timer();
+                    self.search_path(path, &mut searcher, stats)
+                }
+            },
+            |path, matches: Result<Vec<Match>>| {
+                if let Err(err) = report_walk_errors(&walk_errors, sink) {
+                    abort_error = Some(err);
+                    return false;
+                }
+
+                let mut matches = match matches {
+                    // No futher processing required for empty matches.
+                    Ok(matches) if matches.is_empty() => return true,
+                    Ok(matches) => matches,
+
+                    Err(err) => {
+                        stats.add_error();
+                        if let Err(err) = report_error(sink, &path, &err) {
+                            abort_error = Some(err);
+                        }
+                        return false;
+                    }
+                };
+
+                // Skipped files mustn't use up the budget.
+                if let Some(dirty_file_guard) = &self.dirty_file_guard {
+                    match dirty_file_guard.allows_write(&path, &stats, sink) {
+                        Ok(true) => {}
+                        Ok(false) => return true,
+                        Err(err) => {
+                            abort_error = Some(err.context(format!("{}", path.display())));
+                            return false;
+                        }
+                    }
+                }
+
+                // Budget is taken in walk order, so the same matches
+                // are offered on every run.
+                self.limit_matches(&mut matches, &stats);
+                if matches.is_empty() {
+                    return true;
+                }
+
+                stats.add_matches(matches.len());
+
+                let num_replaced = replacer.consume_matches(&path, matches, sink, &mut should_quit);
+                stats.add_changes(replacer.take_num_changes());
+
+                match num_replaced {
+                    Ok(num) => {
+                        if num > 0 {
+                            stats.add_replacements(num);
+                        }
+                    }
+                    Err(err) => {
+                        stats.add_error();
+                        if let Err(err) = report_error(sink, &path, &err) {
+                            abort_error = Some(err);
+                        }
+                        return false;
+                    }
+                }
+
+                !should_quit
+            },
+        );
+
+        if let Some(err) = abort_error {
+            return Err(err);
+        }
+
+        report_walk_errors(&walk_errors, sink)?;
+
+        if !should_quit {
+            self.rename_paths(rename_candidates, &mut replacer, sink, &stats)?;
+        }
+
+        stats.add_elapsed_wall_time(start_time.elapsed());
+
+        // Events replace all printed output.
+        if events.is_none() {
+            match_printer.display_footer(&stats)?;
+
+            if self.options.print_stats {
+                writeln!(&mut writer, "{}", stats)?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn run_with_tui(&mut self) -> Result<Statistics> {
+        let stats = Statistics::new(self.options.max_total);
+        let start_time = Instant::now();
+
+        let mut searcher = self.searcher_factory.build();
+        let replacer = self.replacer_factory.build();
+
+        let mut writer = StandardStream::stdout(self.options.color);
+        let mut match_printer = self.match_printer.build(&mut writer);
+
+        // Everything is searched up front, so decisions can be
+        // revisited in any order before anything is written.
+        let mut paths = vec![];
+        let walk_errors = Mutex::new(vec![]);
+        self.walk_in_order(&stats, &mut vec![], &walk_errors, &mut |path| {
+            paths.push(path);
+            true
+        });
+        report_walk_errors(&walk_errors, &mut match_printer)?;
+
+        let mut review_files = vec![];
+        for path in &paths {
+            let _search_timer = stats.search_timer();
+
+            let mut matches = match self.search_path(path, &mut searcher, &stats) {
+                Ok(matches) => matches,
+                Err(err) => {
+                    stats.add_error();
+                    report_error(&mut match_printer, path, &err)?;
+                    continue;
+                }
+            };
+            if matches.is_empty() {
+                continue;
+            }
+
+            // Only offer changes which could be written, and don't let
+            // skipped files use up the budget.
+            if let Some(dirty_file_guard) = &self.dirty_file_guard {
+                if !dirty_file_guard
+                    .allows_write(path, &stats, &mut match_printer)
+                    .with_context(|| format!("{}", path.display()))?
+                {
+                    continue;
+                }
+            }
+
+            self.limit_matches(&mut matches, &stats);
+            if matches.is_empty() {
+                continue;
+            }
+
+            stats.add_matches(matches.len());
+
+            let proposed = matches
+                .into_iter()
+                .map(|m| {
+                    let replacement = replacer.propose(&m)?;
+                    Ok((m, replacement))
+                })
+                .collect::<Result<Vec<_>>>();
+            let proposed = match proposed {
+                Ok(proposed) => proposed,
+                Err(err) => {
+                    stats.add_error();
+                    report_error(&mut match_printer, path, &err)?;
+                    continue;
+                }
+            };
+
+            review_files.push(ReviewFile::new(path.to_owned(), proposed));
+        }
+
+        if let Some(review_files) = tui::review(review_files)? {
+            for review_file in review_files {
+                let path = review_file.path.clone();
+                let num_replaced = replacer.apply_accepted(&path, review_file.into_accepted());
+
+                match num_replaced {
+                    Ok(num) => {
+                        if num > 0 {
+                            stats.add_replacements(num);
+                        }
+                    }
+                    Err(err) => {
+                        stats.add_error();
+                        report_error(&mut match_printer, &path, &err)?;
+                    }
+                }
+            }
+        }
+
+        stats.add_elapsed_wall_time(start_time.elapsed());
+        match_printer.display_footer(&stats)?;
+
+        if self.options.print_stats {
+            writeln!(&mut writer, "{}", stats)?;
+        }
+
+        Ok(stats)
+    }
+
+    fn run_parallel(&self) -> Result<Statistics> {
+        let writer = BufferWriter::stdout(self.options.color);
+        let stats = Statistics::new(self.options.max_total);
+        let start_time = Instant::now();
+
+        let mut rename_candidates = vec![];
+        let walk_errors = Mutex::new(vec![]);
+        let should_quit = AtomicBool::new(false);
+        // Set once the walk stops for any reason, so that files
+        // already being searched aren't written.
+        let stopped = AtomicBool::new(false);
+        let abort_error = Mutex::new(None);
+
+        if self.options.sort == SortPreference::None {
+            let unordered_candidates = Mutex::new(vec![]);
+            self.replace_unordered(
+                &writer,
+                &stats,
+                &unordered_candidates,
+                &should_quit,
+                &stopped,
+                &abort_error,
+            );
+            rename_candidates = unordered_candidates.into_inner().unwrap();
+        } else {
+            // Files are still searched in parallel, but printed in the
+            // order they're walked.
+            ordered::process_in_order(
+                self.options.num_threads(),
+                SEARCH_AHEAD_FILES,
+                |emit| self.walk_in_order(&stats, &mut rename_candidates, &walk_errors, emit),
+                || {
+                    let mut searcher = self.searcher_factory.build();
+                    let mut replacer = self.replacer_factory.build();
+                    let writer = &writer;
+                    let stats = &stats;
+                    let should_quit = &should_quit;
+                    let stopped = &stopped;
+                    let abort_error = &abort_error;
+
+                    move |path: &Path| {
+                        let _search_timer = stats.search_timer();
+
+                        let mut buffer = writer.buffer();
+                        let state = self.replace_in_file(
+                            path,
+                            &mut searcher,
+                            &mut replacer,
+                            &mut buffer,
+                            stats,
+                            should_quit,
+                            stopped,
+                            abort_error,
+                        );
+
+                        (buffer, state)
+                    }
+                },
+                |path, (buffer, state)| {
+                    let keep_going = match writer.print(&buffer) {
+                        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => false,
+                        Err(err) => {
+                            stats.add_error();
+                            eprintln!("{}: {}", path.display(), err);
+                            matches!(state, WalkState::Continue)
+                        }
+                        Ok(()) => matches!(state, WalkState::Continue),
+                    };
+
+                    // Workers run ahead of the files printed so far.
+                    if !keep_going {
+                        stopped.store(true, Ordering::Relaxed);
+                    }
+                    keep_going
+                },
+            );
+        }
+
+        if let Some(err) = abort_error.into_inner().unwrap() {
+            return Err(err);
+        }
+
+        let mut buffer = writer.buffer();
+        let mut match_printer = self.match_printer.build(&mut buffer);
+        report_walk_errors(&walk_errors, &mut match_printer)?;
+
+        if !should_quit.load(Ordering::Relaxed) {
+            let mut replacer = self.replacer_factory.build();
+            self.rename_paths(rename_candidates, &mut replacer, &mut match_printer, &stats)?;
+        }
+
+        stats.add_elapsed_wall_time(start_time.elapsed());
+        match_printer.display_footer(&stats)?;
+
+        if self.options.print_stats {
+            writeln!(&mut buffer, "{}", stats)?;
+        }
+
+        if let Err(err) = writer.print(&buffer) {
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                return Err(anyhow!(err));
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Walk files in parallel, printing each file as soon as it has
+    /// been searched.
+    fn replace_unordered(
+        &self,
+        writer: &BufferWriter,
+        stats: &Statistics,
+        rename_candidates: &Mutex<Vec<PathBuf>>,
+        should_quit: &AtomicBool,
+        stopped: &AtomicBool,
+        abort_error: &Mutex<Option<anyhow::Error>>,
+    ) {
+        let file_walker = match &self.file_walker {
+            Some(file_walker) => file_walker.build_parallel(),
+            None => return,
+        };
+        file_walker.run(|| {
+            let mut searcher = self.searcher_factory.build();
+            let mut replacer = self.replacer_factory.build();
+
+            Box::new(move |dir_entry| {
+                let _search_timer = stats.search_timer();
+
+                if let Ok(ref entry) = dir_entry {
+                    if self.path_renamer.is_some()
+                        && self.path_matcher.path_matches(entry.path())
+                        && replacer.should_rename(entry.path())
+                    {
+                        stats.add_path_match();
+                        rename_candidates
+                            .lock()
+                            .unwrap()
+                            .push(entry.path().to_owned());
+                    }
+                }
+
+                let path = match dir_entry {
+                    Ok(ref entry) => {
+                        if !self.path_matcher.should_search(entry) {
+                            // TODO: this counts directories
+                            stats.visit_file(false);
+                            return WalkState::Continue;
+                        }
+
+                        entry.path()
+                    }
+
+                    Err(err) => {
+                        stats.add_error();
+                        eprintln!("error: {}", err);
+                        return WalkState::Continue;
+                    }
+                };
+
+                stats.visit_file(true);
+
+                let mut buffer = writer.buffer();
+                let state = self.replace_in_file(
+                    path,
+                    &mut searcher,
+                    &mut replacer,
+                    &mut buffer,
+                    stats,
+                    should_quit,
+                    stopped,
+                    abort_error,
+                );
+
+                let state = match writer.print(&buffer) {
+                    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => WalkState::Quit,
+                    Err(err) => {
+                        stats.add_error();
+                        eprintln!("{}: {}", path.display(), err);
+                        state
+                    }
+                    Ok(()) => state,
+                };
+
+                // Other threads may still be searching their files.
+                if let WalkState::Quit = state {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+                state
+            })
+        });
+    }
+
+    /// Search and replace within a single file, printing the results
+    /// into `buffer`. Returns whether the walk should continue.
+    ///
+    /// Nothing is written once `stopped` is set, as the walk may have
+    /// stopped while the file was being searched.
+    #[allow(clippy::too_many_arguments)]
+    fn replace_in_file(
+        &self,
+        path: &Path,
+        searcher: &mut Searcher,
+        replacer: &mut Replacer,
+        buffer: &mut Buffer,
+        stats: &Statistics,
+        run_should_quit: &AtomicBool,
+        stopped: &AtomicBool,
+        abort_error: &Mutex<Option<anyhow::Error>>,
+    ) -> WalkState {
+        let mut match_printer = self.match_printer.build(buffer);
+
+        let mut matches = match self.search_path(path, searcher, stats) {
+            // No futher processing required for empty matches.
+            Ok(matches) if matches.is_empty() => return WalkState::Continue,
+            Ok(matches) => matches,
+
+            Err(err) => {
+                stats.add_error();
+                let _ = report_error(&mut match_printer, path, &err);
+                return WalkState::Quit;
+            }
+        };
+
+        if let Some(dirty_file_guard) = &self.dirty_file_guard {
+            match dirty_file_guard.allows_write(path, stats, &mut match_printer) {
+                Ok(true) => {}
+                Ok(false) => return WalkState::Continue,
+                Err(err) => {
+                    let err = err.context(format!("{}", path.display()));
+                    abort_error.lock().unwrap().get_or_insert(err);
+                    return WalkState::Quit;
+                }
+            }
+        }
+
+        if stopped.load(Ordering::Relaxed) {
+            return WalkState::Quit;
+        }
+
+        // Only after the dirty check, so that skipped files don't use
+        // up the budget.
+        self.limit_matches(&mut matches, stats);
+        if matches.is_empty() {
+            return WalkState::Continue;
+        }
+
+        stats.add_matches(matches.len());
+
+        let mut should_quit = false;
+        let num_replaced =
+            replacer.consume_matches(path, matches, &mut match_printer, &mut should_quit);
+        stats.add_changes(replacer.take_num_changes());
+
+        match num_replaced {
+            Ok(num) => {
+                if num > 0 {
+                    stats.add_replacements(num);
+                }
+            }
+            Err(err) => {
+                stats.add_error();
+                let _ = report_error(&mut match_printer, path, &err);
+                return WalkState::Quit;
+            }
+        }
+
+        if should_quit {
+            run_should_quit.store(true, Ordering::Relaxed);
+            WalkState::Quit
+        } else {
+            WalkState::Continue
+        }
+    }
+
+    /// Rename paths collected while walking, once their contents have
+    /// been processed.
+    fn rename_paths(
+        &self,
+        paths: Vec<PathBuf>,
+        replacer: &mut Replacer,
+        events: &mut dyn EventSink,
+        stats: &Statistics,
+    ) -> Result<()> {
+        let path_renamer = match self.path_renamer {
+            Some(path_renamer) if !paths.is_empty() => path_renamer,
+            _ => return Ok(()),
+        };
+
+        let mut should_quit = false;
+        let num_renamed =
+            replacer.consume_renames(paths, path_renamer, events, &mut should_quit)?;
+        stats.add_renames(num_renamed);
+
+        Ok(())
+    }
+}
+
+struct DirtyFileGuard {
+    preference: DirtyPreference,
+    dirty_files: DirtyFiles,
+}
+
+impl DirtyFileGuard {
+    const ABORT_MESSAGE: &'static str = "file has uncommitted changes, aborting (see --dirty)";
+
+    /// Whether `path` may be modified, reporting skipped files to
+    /// `events`. Errors if the file is dirty and we should abort.
+    ///
+    /// Files which can't be checked are reported and skipped, without
+    /// stopping the run.
+    fn allows_write(
+        &self,
+        path: &Path,
+        stats: &Statistics,
+        events: &mut dyn EventSink,
+    ) -> Result<bool> {
+        match self.dirty_files.is_dirty(path) {
+            Ok(false) => return Ok(true),
+            Ok(true) => {}
+            Err(err) => {
+                stats.add_error();
+                let err = anyhow!("couldn't check for uncommitted changes: {:#}", err);
+                report_error(events, path, &err)?;
+                return Ok(false);
+            }
+        }
+
+        match self.preference {
+            DirtyPreference::Allow => Ok(true),
+            DirtyPreference::Skip => {
+                events.handle(Event::Skipped {
+                    path,
+                    reason: "skipped, file has uncommitted changes (see --dirty)",
+                })?;
+                stats.skip_dirty_file();
+                Ok(false)
+            }
+            DirtyPreference::Abort => Err(anyhow!(Self::ABORT_MESSAGE)),
+        }
+    }
+}
+
+/// Whether `path` is one of `changed_files`, as listed by git.
+fn is_changed(changed_files: &HashSet<PathBuf>, path: &Path) -> bool {
+    std::fs::canonicalize(path).is_ok_and(|path| changed_files.contains(&path))
+}
+
+/// Report an error on `path` which didn't stop the run.
+fn report_error(events: &mut dyn EventSink, path: &Path, err: &anyhow::Error) -> Result<()> {
+    events.handle(Event::Error {
+        path: Some(path),
+        message: &err.to_string(),
+    })
+}
+
+/// Report errors met while walking since the last call.
+fn report_walk_errors(walk_errors: &Mutex<Vec<String>>, events: &mut dyn EventSink) -> Result<()> {
+    let walk_errors = std::mem::take(&mut *walk_errors.lock().unwrap());
+    for message in walk_errors {
+        events.handle(Event::Error {
+            path: None,
+            message: &message,
+        })?;
+    }
+
+    Ok(())
+}
+
+struct PathMatcher {
+    included_paths: Option<RegexSet>,
+    excluded_paths: RegexSet,
+}
+
+impl PathMatcher {
+    fn should_search(&self, dir_entry: &DirEntry) -> bool {
+        // Don't need to consider directories
+        let is_file = dir_entry.file_type().map_or(false, |it| it.is_file());
+
+        is_file && self.path_matches(dir_entry.path())
+    }
+
+    fn path_matches(&self, path: &Path) -> bool {
+        let path_str = path
+            .to_str()
+            .with_context(|| format!("Failed to interpret path name as UTF-8 string: {:?}", path))
+            .unwrap();
+
+        if let Some(included_paths) = &self.included_paths {
+            return included_paths.is_match(path_str);
+        }
+
+        !self.excluded_paths.is_match(path_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_matches() {
+        let stats = Statistics::new(Some(5));
+        assert_eq!(stats.reserve_matches(3), 3);
+        assert_eq!(stats.reserve_matches(3), 2);
+        assert_eq!(stats.reserve_matches(3), 0);
+        assert_eq!(stats.remaining_budget(), 0);
+
+        let stats = Statistics::new(None);
+        assert_eq!(stats.reserve_matches(3), 3);
+    }
+
+    #[allow(clippy::bool_assert_comparison, clippy::needless_borrow)]
+    mod path_matcher {
+        use super::*;
+
+        fn as_regex_set(v: Vec<&str>) -> RegexSet {
+            let escaped = v.iter().map(|r| regex::escape(r));
+            RegexSet::new(escaped).unwrap()
+        }
+
+        #[test]
+        fn test_empty_included_set() {
+            let disallow_list: Vec<&str> = vec![];
+
+            let matcher = PathMatcher {
+                included_paths: None,
+                excluded_paths: as_regex_set(disallow_list),
+            };
+
+            assert_eq!(matcher.path_matches(&Path::new("foo")), true);
+        }
+
+        #[test]
+        fn test_included_set() {
+            let allow_list: Vec<&str> = vec!["foo", "bar"];
+            let disallow_list: Vec<&str> = vec![];
+
+            let matcher = PathMatcher {
+                included_paths: Some(as_regex_set(allow_list)),
+                excluded_paths: as_regex_set(disallow_list),
+            };
+
+            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), true);
+            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), true);
+            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), false);
+        }
+
+        #[test]
+        fn test_excluded_set() {
+            let disallow_list = vec!["foo", "bar"];
+            let matcher = PathMatcher {
+                included_paths: None,
+                excluded_paths: as_regex_set(disallow_list),
+            };
+
+            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), false);
+            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), false);
+            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), true);
+        }
+
+        // Inclusion should take precedence
+        #[test]
+        fn test_included_and_excluded_set() {
+            let allow_list = vec!["foo", "bar"];
+            let disallow_list = vec!["foo", "bar"];
+
+            let matcher = PathMatcher {
+                included_paths: Some(as_regex_set(allow_list)),
+                excluded_paths: as_regex_set(disallow_list),
+            };
+
+            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), true);
+            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), true);
+            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), false);
+        }
+    }
+}
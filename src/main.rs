@@ -1,159 +1,89 @@
 use std::borrow::Cow;
-use std::fmt;
-use std::io;
-use std::io::{BufRead, Write};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{BufRead, BufWriter, Read};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{ensure, Context, Result};
 use atty::Stream;
-use clap::Parser;
-use grep::regex::{RegexMatcher, RegexMatcherBuilder};
-use grep::searcher::{BinaryDetection, SearcherBuilder};
-use ignore::{DirEntry, WalkBuilder, WalkState};
-use regex::RegexSet;
-use termcolor::{BufferWriter, ColorChoice, StandardStream};
-
-mod printer;
-mod replace;
-mod search;
-
-use crate::printer::{MatchPrintMode, MatchPrinterBuilder};
-use crate::replace::{ReplacementDecider, ReplacementDecision, ReplacerFactory};
-use crate::search::RegexSearcherFactory;
-
-#[derive(Debug)]
-pub struct Statistics {
-    wall_time_ns: AtomicU64,
-    search_time_ns: AtomicU64,
-    files_total: AtomicUsize,
-    files_searched: AtomicUsize,
-    files_ignored: AtomicUsize,
-    files_with_matches: AtomicUsize,
-    files_with_replacements: AtomicUsize,
-    num_matches: AtomicUsize,
-    num_replacements: AtomicUsize,
-}
+use clap::{AppSettings, ErrorKind, Parser};
+use fnr::{
+    Case, ColorChoice, DirtyPreference, FindAndReplace, MatchPrintMode, PromptInput, Scope,
+    SortPreference,
+};
 
-struct StatSearchTimer<'a> {
-    started_at: Instant,
-    stats: &'a Statistics,
-}
+mod config_file;
 
-impl<'a> Drop for StatSearchTimer<'a> {
-    fn drop(&mut self) {
-        let elapsed = self.started_at.elapsed();
-        self.stats.add_elapsed_search_time(elapsed);
-    }
-}
+const DEFAULT_CONTEXT_LINES: usize = 2;
 
-impl Statistics {
-    fn new() -> Statistics {
-        Statistics {
-            wall_time_ns: 0.into(),
-            search_time_ns: 0.into(),
-            files_total: 0.into(),
-            files_searched: 0.into(),
-            files_ignored: 0.into(),
-            files_with_matches: 0.into(),
-            files_with_replacements: 0.into(),
-
-            num_matches: 0.into(),
-            num_replacements: 0.into(),
-        }
-    }
+#[derive(Debug, clap::ArgEnum, Clone)]
+enum ColorPreference {
+    Always,
+    Auto,
+    Never,
+}
 
-    fn num_matches(&self) -> usize {
-        self.num_matches.load(Ordering::Relaxed)
-    }
+// Mirrors of the library's options, which are kept free of clap.
 
-    fn num_replacements(&self) -> usize {
-        self.num_replacements.load(Ordering::Relaxed)
-    }
+#[derive(Debug, clap::ArgEnum, Clone, Copy)]
+enum SortArg {
+    Path,
+    /// Least recently modified first, across every directory.
+    Modified,
+    /// Whichever order files are found in, which may change between
+    /// runs when searching in parallel.
+    None,
+}
 
-    fn search_timer(&self) -> StatSearchTimer {
-        StatSearchTimer {
-            stats: self,
-            started_at: Instant::now(),
+impl From<SortArg> for SortPreference {
+    fn from(sort: SortArg) -> SortPreference {
+        match sort {
+            SortArg::Path => SortPreference::Path,
+            SortArg::Modified => SortPreference::Modified,
+            SortArg::None => SortPreference::None,
         }
     }
+}
 
-    #[inline]
-    fn add_elapsed_wall_time(&self, d: Duration) {
-        self.wall_time_ns
-            .fetch_add(d.as_nanos().try_into().unwrap_or(0), Ordering::Relaxed);
-    }
-
-    #[inline]
-    fn add_elapsed_search_time(&self, d: Duration) {
-        self.search_time_ns
-            .fetch_add(d.as_nanos().try_into().unwrap_or(0), Ordering::Relaxed);
-    }
+#[derive(Debug, clap::ArgEnum, Clone, Copy)]
+enum DirtyArg {
+    Skip,
+    Abort,
+    Allow,
+}
 
-    #[inline]
-    fn visit_file(&self, searched: bool) {
-        self.files_total.fetch_add(1, Ordering::Relaxed);
-        if searched {
-            self.files_searched.fetch_add(1, Ordering::Relaxed);
-        } else {
-            self.files_ignored.fetch_add(1, Ordering::Relaxed);
+impl From<DirtyArg> for DirtyPreference {
+    fn from(dirty: DirtyArg) -> DirtyPreference {
+        match dirty {
+            DirtyArg::Skip => DirtyPreference::Skip,
+            DirtyArg::Abort => DirtyPreference::Abort,
+            DirtyArg::Allow => DirtyPreference::Allow,
         }
     }
-
-    #[inline]
-    fn add_matches(&self, num_matches: usize) {
-        self.files_with_matches.fetch_add(1, Ordering::Relaxed);
-        self.num_matches.fetch_add(num_matches, Ordering::Relaxed);
-    }
-
-    #[inline]
-    fn add_replacements(&self, num_replacements: usize) {
-        self.files_with_replacements.fetch_add(1, Ordering::Relaxed);
-        self.num_replacements
-            .fetch_add(num_replacements, Ordering::Relaxed);
-    }
 }
 
-impl fmt::Display for Statistics {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "\
-wall time               {wall_time_secs} s
-search time             {search_time_secs} s
-num matches             {num_matches:?}
-num replacements        {num_replacements:?}
-total files             {files_total:?}
-  ... ignored           {files_ignored:?}
-  ... searched          {files_searched:?}
-  ... with matches      {files_with_matches:?}
-  ... with replacements {files_with_replacements:?}",
-            wall_time_secs =
-                Duration::from_nanos(self.wall_time_ns.load(Ordering::Relaxed)).as_secs_f32(),
-            search_time_secs =
-                Duration::from_nanos(self.search_time_ns.load(Ordering::Relaxed)).as_secs_f32(),
-            num_matches = self.num_matches,
-            num_replacements = self.num_replacements,
-            files_total = self.files_total,
-            files_ignored = self.files_ignored,
-            files_searched = self.files_searched,
-            files_with_matches = self.files_with_matches,
-            files_with_replacements = self.files_with_replacements,
-        )
-    }
+#[derive(Debug, clap::ArgEnum, Clone, Copy)]
+enum ScopeArg {
+    /// Everything outside comments and string literals.
+    Code,
+    Comments,
+    Strings,
 }
 
-#[derive(Debug, clap::ArgEnum, Clone)]
-enum ColorPreference {
-    Always,
-    Auto,
-    Never,
+impl From<ScopeArg> for Scope {
+    fn from(scope: ScopeArg) -> Scope {
+        match scope {
+            ScopeArg::Code => Scope::Code,
+            ScopeArg::Comments => Scope::Comments,
+            ScopeArg::Strings => Scope::Strings,
+        }
+    }
 }
 
 #[derive(Debug, clap::Parser)]
-#[clap(name = "fnr", about, version, author)]
+#[clap(name = "fnr", about, version, author, setting = AppSettings::AllArgsOverrideSelf)]
 /// Recursively find and replace. Like sed, but memorable.
 // TODO: Potential features:
 //
@@ -195,18 +125,176 @@ struct Config {
     #[clap(short = 'W', long)]
     write: bool,
 
+    /// What to do when writing to a file with uncommitted changes in
+    /// git, with --write, --tui or the prompt.
+    ///
+    /// By default these files are skipped, so that any replacement
+    /// can be reverted with `git checkout`. Untracked files can't be
+    /// reverted either, so they count as dirty. With `abort`, nothing
+    /// is written if any file with matches is dirty. Files are always
+    /// allowed with --changed-since, --staged and
+    /// --only-changed-lines, as they're dirty by definition.
+    #[clap(arg_enum, long, ignore_case = true, default_value = "skip")]
+    dirty: DirtyArg,
+
+    /// Review replacements in a full screen terminal interface before
+    /// writing them.
+    #[clap(long, conflicts_with_all = &["write", "dry-run", "rename-paths"])]
+    tui: bool,
+
     /// Perform search and replace without modifying files.
     #[clap(long)]
     dry_run: bool,
 
+    /// Exit with status 1 if anything would be replaced, without
+    /// modifying files. Useful in CI to check that a pattern is gone.
+    #[clap(long, conflicts_with_all = &["write", "tui"])]
+    check: bool,
+
+    /// Only search for FIND, printing matches without replacing them.
+    ///
+    /// This is also the case when REPLACE isn't given. With --search,
+    /// every argument after FIND is a path.
+    #[clap(long, conflicts_with_all = &["write", "tui", "rename-paths", "swap"])]
+    search: bool,
+
+    /// Delete matching lines.
+    ///
+    /// Every argument after FIND is a path, as there's no REPLACE.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "insert-before", "insert-after", "swap", "ts-query", "search", "files-with-matches",
+            "count",
+        ]
+    )]
+    delete_line: bool,
+
+    /// Insert a line before each matching line, which may reference
+    /// capture groups like REPLACE.
+    ///
+    /// Matching lines are otherwise left as they are, and every
+    /// argument after FIND is a path.
+    #[clap(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = &["swap", "ts-query", "search", "files-with-matches", "count"]
+    )]
+    insert_before: Option<String>,
+
+    /// Insert a line after each matching line, as with
+    /// --insert-before.
+    #[clap(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = &["swap", "ts-query", "search", "files-with-matches", "count"]
+    )]
+    insert_after: Option<String>,
+
+    /// Replace matches in text read from standard input, writing the
+    /// result to standard output, like sed.
+    ///
+    /// Nothing is printed besides the text, and no paths are given.
+    #[clap(
+        long,
+        alias = "stdout",
+        conflicts_with_all = &[
+            "write", "tui", "dry-run", "check", "search", "files-with-matches", "count",
+            "rename-paths", "ts-query", "scope", "lines", "between", "max-count", "max-total",
+            "first-in-file", "structural", "changed-since", "staged", "only-changed-lines",
+            "sort", "dirty", "include", "exclude", "hidden", "all-files",
+        ]
+    )]
+    stdin_content: bool,
+
+    /// Only print the paths of files with matches. Implies --search.
+    #[clap(
+        short = 'l',
+        long,
+        conflicts_with_all = &["write", "tui", "rename-paths", "swap", "count", "quiet", "compact"]
+    )]
+    files_with_matches: bool,
+
+    /// Only print how many matches each file has. Implies --search.
+    #[clap(
+        long,
+        conflicts_with_all = &["write", "tui", "rename-paths", "swap", "quiet", "compact"]
+    )]
+    count: bool,
+
     /// Treat FIND as a string rather than a regular expression.
     #[clap(short = 'Q', long)]
     literal: bool,
 
+    /// Treat FIND as a structural pattern, where holes like `:[args]`
+    /// match text with balanced brackets, possibly across lines.
+    ///
+    /// Holes are referred to in REPLACE the same way, e.g.
+    /// `fnr --structural 'old(:[args])' 'new(:[args], None)'`.
+    /// Patterns always match case sensitively. Single-quoted strings
+    /// are only skipped when they hold one character, so a bracket in
+    /// a longer one, as in JavaScript or Python, can end a hole.
+    #[clap(
+        long,
+        conflicts_with_all = &["literal", "word", "swap", "ignore-case", "case-sensitive"]
+    )]
+    structural: bool,
+
+    /// Treat FIND as a tree-sitter query, and replace the text spanned
+    /// by each match's captured nodes with REPLACE.
+    ///
+    /// REPLACE refers to a node captured as `@name` by `$name`, e.g.
+    /// `fnr --ts-query '(call_expression function: (identifier) @f
+    /// (#eq? @f "foo") arguments: (_) @args)' 'bar$args'`. Only Rust
+    /// and TypeScript files are searched.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "literal", "word", "swap", "structural", "occurrence", "scope", "lines", "between",
+            "rename-paths", "insert-before", "insert-after",
+        ]
+    )]
+    ts_query: bool,
+
     /// Match FIND only at word boundary.
     #[clap(short, long)]
     word: bool,
 
+    /// Exchange occurrences of A and B with each other.
+    ///
+    /// Both are matched literally and in a single pass, so no
+    /// placeholder is needed. FIND and REPLACE are not given when
+    /// swapping.
+    #[clap(long, number_of_values = 2, value_names = &["A", "B"])]
+    swap: Option<Vec<String>>,
+
+    /// Read FIND from a file, or from standard input if `-`.
+    ///
+    /// One trailing newline is removed from the file's contents,
+    /// unless --keep-trailing-newline is given. FIND is still a
+    /// regular expression unless --literal is given, and may span
+    /// lines. FIND is then not given as an argument.
+    #[clap(long, value_name = "PATH", parse(from_os_str), conflicts_with = "swap")]
+    find_file: Option<PathBuf>,
+
+    /// Read REPLACE from a file, or from standard input if `-`, as
+    /// with --find-file.
+    ///
+    /// Capture groups are still referenced with `$`, so a literal `$`
+    /// must be written as `$$`.
+    #[clap(
+        long,
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with_all = &["swap", "search", "files-with-matches", "count", "delete-line"]
+    )]
+    replace_file: Option<PathBuf>,
+
+    /// Keep the trailing newline of files given to --find-file and
+    /// --replace-file.
+    #[clap(long)]
+    keep_trailing_newline: bool,
+
     /// Search ALL files in given paths for matches.
     #[clap(short, long, conflicts_with = "hidden")]
     all_files: bool,
@@ -215,6 +303,70 @@ struct Config {
     #[clap(short = 'H', long, conflicts_with = "all-files")]
     hidden: bool,
 
+    /// Also rename files and directories whose names match FIND.
+    #[clap(long)]
+    rename_paths: bool,
+
+    /// Use `git mv` when renaming paths.
+    #[clap(long, requires = "rename-paths")]
+    git_mv: bool,
+
+    /// Only search files changed relative to the given git revision,
+    /// including uncommitted changes and untracked files.
+    #[clap(long, value_name = "REV")]
+    changed_since: Option<String>,
+
+    /// Only search files with changes staged in git.
+    #[clap(long)]
+    staged: bool,
+
+    /// Only replace matches on lines added or modified relative to
+    /// the given git revision [default: HEAD]. Every line of an
+    /// untracked file counts as added.
+    #[clap(long, value_name = "REV", require_equals = true)]
+    only_changed_lines: Option<Option<String>>,
+
+    /// Only replace the first NUM matching lines of each file.
+    #[clap(short = 'm', long, value_name = "NUM")]
+    max_count: Option<usize>,
+
+    /// Only replace the Nth match on each line, leaving lines with
+    /// fewer matches alone.
+    #[clap(long, value_name = "N")]
+    occurrence: Option<usize>,
+
+    /// Only replace the first match in each file.
+    #[clap(long, conflicts_with_all = &["occurrence", "max-count"])]
+    first_in_file: bool,
+
+    /// Only replace matches on lines START to END, e.g. 10:200.
+    /// Either end may be left out.
+    #[clap(long, value_name = "START:END", parse(try_from_str = parse_line_range))]
+    lines: Option<RangeInclusive<u64>>,
+
+    /// Only replace matches between lines matching the regular
+    /// expressions START and END, e.g. in a generated section.
+    ///
+    /// Regions may be nested, in which case they end at the END
+    /// closing the outermost START. A region without an END runs to
+    /// the end of the file.
+    #[clap(long, number_of_values = 2, value_names = &["START", "END"])]
+    between: Option<Vec<String>>,
+
+    /// Only replace matches in code, comments or string literals.
+    ///
+    /// Rust, C-family languages, Python, JavaScript, TypeScript, Go
+    /// and shell scripts are recognized by their extension. Other
+    /// files are skipped.
+    #[clap(arg_enum, long, ignore_case = true)]
+    scope: Option<ScopeArg>,
+
+    /// Only replace the first NUM matching lines overall, e.g. for a
+    /// cautious trial run. Combine with --sort for the same lines to
+    /// be picked each time.
+    #[clap(long, value_name = "NUM")]
+    max_total: Option<usize>,
+
     /// Print lines after matches.
     #[clap(short = 'A', long)]
     after: Option<usize>,
@@ -239,23 +391,40 @@ struct Config {
     #[clap(arg_enum, long, ignore_case = true, default_value = "auto")]
     color: ColorPreference,
 
+    /// Print files in a stable order.
+    ///
+    /// Files are printed as soon as they're searched by default, so
+    /// the order can change between runs. Sorting by modified time
+    /// puts the least recently modified files first.
+    #[clap(arg_enum, long, ignore_case = true, default_value = "none")]
+    sort: SortArg,
+
     /// Print debug statistics about match.
     #[clap(long = "stats")]
     print_stats: bool,
 
+    /// Ignore configuration files.
+    ///
+    /// Default arguments are otherwise read from $FNR_CONFIG_PATH (or
+    /// ~/.config/fnr/config), then from .fnr.toml in the searched
+    /// directory or its closest parent.
+    #[clap(long)]
+    no_config: bool,
+
     /// What to search for. Literal string or regular expression.
     ///
     /// For supported regular expression syntax, see:
     /// https://docs.rs/regex/latest/regex/#syntax
-    #[clap(name = "FIND", required = true)]
-    find: String,
+    #[clap(name = "FIND", required_unless_present_any = &["swap", "find-file"])]
+    find: Option<String>,
 
-    /// What to replace it with.
+    /// What to replace it with. Matches are only searched for if not
+    /// given.
     ///
     /// May contain numbered references to capture groups given in
     /// FIND in the form $1, $2, etc.
-    #[clap(name = "REPLACE", required = true)]
-    replace: String,
+    #[clap(name = "REPLACE")]
+    replace: Option<String>,
 
     /// Locations to search. Current directory if not given.
     ///
@@ -267,43 +436,277 @@ struct Config {
 }
 
 impl Config {
-    fn path_matcher(&self) -> Result<PathMatcher> {
-        let included_paths = self.include.as_ref().map(|included_paths| {
-            let escaped = included_paths.iter().map(|p| regex::escape(p));
-            RegexSet::new(escaped).unwrap()
-        });
-
-        let excluded_paths = {
-            let escaped = self.exclude.iter().map(|p| regex::escape(p));
-            RegexSet::new(escaped)?
-        };
+    fn parse_args() -> Result<Config> {
+        let mut config = Config::parse_with_config_files()?;
+        config.read_pattern_files()?;
+        Ok(config)
+    }
+
+    fn parse_with_config_files() -> Result<Config> {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        let config = Config::parse_from_args(&args);
+        if config.no_config {
+            return Ok(config);
+        }
 
-        Ok(PathMatcher {
-            included_paths,
-            excluded_paths,
-        })
+        // Configured arguments go first so that those given on the
+        // command line override them.
+        let config_args = config_file::args(config.search_root())?;
+        let config_args = Self::without_conflicts(config_args, &args);
+        if config_args.is_empty() {
+            return Ok(config);
+        }
+
+        args.splice(1..1, config_args);
+        Ok(Config::parse_from_args(&args))
+    }
+
+    /// Drop configured arguments which conflict with `args` from the
+    /// command line, such as `--context` with `--after`, so that the
+    /// command line still wins.
+    fn without_conflicts(config_args: Vec<OsString>, args: &[OsString]) -> Vec<OsString> {
+        // Each flag is grouped with any values given as separate
+        // arguments after it.
+        let mut flags: Vec<Vec<OsString>> = vec![];
+        for arg in config_args {
+            match flags.last_mut() {
+                Some(flag) if !arg.to_string_lossy().starts_with('-') => flag.push(arg),
+                _ => flags.push(vec![arg]),
+            }
+        }
+
+        flags
+            .into_iter()
+            .filter(|flag| {
+                let with_flag = args[..1].iter().chain(flag).chain(&args[1..]);
+                !matches!(
+                    Config::try_parse_from(with_flag),
+                    Err(err) if err.kind == ErrorKind::ArgumentConflict
+                )
+            })
+            .flatten()
+            .collect()
+    }
+
+    fn parse_from_args(args: &[OsString]) -> Config {
+        let mut config = Config::parse_from(args);
+
+        // There's no FIND when swapping or reading it from a file, and
+        // no REPLACE when swapping, reading it from a file, only
+        // searching or editing whole lines. Clap assigns positional
+        // arguments in order regardless, so shift them along into the
+        // paths.
+        let takes_find = config.swap.is_none() && config.find_file.is_none();
+        let takes_replace = config.swap.is_none()
+            && config.replace_file.is_none()
+            && !(config.search || config.files_with_matches || config.count)
+            && !config.edits_lines();
+
+        let mut positionals: VecDeque<String> = config
+            .find
+            .take()
+            .into_iter()
+            .chain(config.replace.take())
+            .collect();
+        if takes_find {
+            config.find = positionals.pop_front();
+        }
+        if takes_replace {
+            config.replace = positionals.pop_front();
+        }
+
+        let mut paths: Vec<PathBuf> = positionals.into_iter().map(PathBuf::from).collect();
+        paths.append(&mut config.paths);
+        config.paths = paths;
+
+        config
+    }
+
+    /// Fill in FIND and REPLACE from the files they're read from.
+    fn read_pattern_files(&mut self) -> Result<()> {
+        ensure!(
+            !(is_stdin(&self.find_file) && is_stdin(&self.replace_file)),
+            "only one of --find-file and --replace-file can read standard input"
+        );
+
+        if let Some(path) = &self.find_file {
+            self.find = Some(self.read_pattern_file(path)?);
+        }
+        if let Some(path) = &self.replace_file {
+            self.replace = Some(self.read_pattern_file(path)?);
+        }
+
+        Ok(())
     }
 
-    fn pattern(&self) -> Cow<str> {
-        if self.literal {
-            regex::escape(&self.find).into()
+    fn read_pattern_file(&self, path: &Path) -> Result<String> {
+        let mut contents = if path == Path::new("-") {
+            let mut contents = String::new();
+            std::io::stdin().read_to_string(&mut contents)?;
+            contents
         } else {
-            self.find.as_str().into()
+            fs::read_to_string(path).with_context(|| format!("{}", path.display()))?
+        };
+
+        if !self.keep_trailing_newline {
+            if contents.ends_with("\r\n") {
+                contents.truncate(contents.len() - 2);
+            } else if contents.ends_with('\n') {
+                contents.pop();
+            }
+        }
+
+        Ok(contents)
+    }
+
+    /// Whether standard input is taken up by FIND or REPLACE.
+    fn reads_pattern_from_stdin(&self) -> bool {
+        is_stdin(&self.find_file) || is_stdin(&self.replace_file)
+    }
+
+    /// Whether to only search, as there's nothing to replace with.
+    fn searches_only(&self) -> bool {
+        self.swap.is_none() && self.replace.is_none() && !self.edits_lines()
+    }
+
+    /// Whether matching lines are deleted or have lines inserted
+    /// around them.
+    fn edits_lines(&self) -> bool {
+        self.delete_line || self.insert_before.is_some() || self.insert_after.is_some()
+    }
+
+    /// Where to look for a repository's config file.
+    fn search_root(&self) -> &Path {
+        match self.paths.first() {
+            Some(path) if path.is_dir() => path,
+            Some(path) => path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new(".")),
+            None => Path::new("."),
         }
     }
 
-    fn regex_matcher(&self) -> Result<RegexMatcher> {
-        let pattern = self.pattern();
+    fn find_and_replace(&self) -> Result<FindAndReplace> {
+        ensure!(
+            !self.searches_only()
+                || !(self.write || self.tui || self.rename_paths || self.stdin_content),
+            "REPLACE is required to write, review, rename or filter standard input"
+        );
+
+        let mut find_and_replace = match &self.swap {
+            Some(pair) => FindAndReplace::swap(&pair[0], &pair[1]),
+            None if self.searches_only() => {
+                FindAndReplace::search(self.find.as_deref().unwrap_or_default())
+            }
+            None if self.edits_lines() => {
+                FindAndReplace::matching_lines(self.find.as_deref().unwrap_or_default())
+            }
+            None => FindAndReplace::new(
+                self.find.as_deref().unwrap_or_default(),
+                self.replace.as_deref().unwrap_or_default(),
+            ),
+        };
 
-        RegexMatcherBuilder::new()
-            .case_insensitive(!self.case_sensitive && self.ignore_case)
-            .case_smart(!self.case_sensitive && self.smart_case.unwrap_or(true))
+        find_and_replace = find_and_replace
+            .paths(self.given_paths()?.iter())
+            .literal(self.literal)
+            .structural(self.structural)
+            .ts_query(self.ts_query)
+            .case(self.case())
             .word(self.word)
-            .build(&pattern)
-            .with_context(|| format!("Failed to parse pattern '{}'", pattern))
+            .hidden(self.hidden)
+            .all_files(self.all_files)
+            .context(
+                self.before
+                    .or(self.context)
+                    .unwrap_or(DEFAULT_CONTEXT_LINES),
+                self.after.or(self.context).unwrap_or(DEFAULT_CONTEXT_LINES),
+            )
+            .rename_paths(self.rename_paths)
+            .git_mv(self.git_mv)
+            .staged(self.staged)
+            .dirty(self.dirty.into())
+            .sort(self.sort.into())
+            .delete_lines(self.delete_line)
+            .print_mode(self.print_mode())
+            .color(self.color_choice())
+            .print_stats(self.print_stats);
+
+        for pattern in self.include.iter().flatten() {
+            find_and_replace = find_and_replace.include(pattern);
+        }
+        for pattern in &self.exclude {
+            find_and_replace = find_and_replace.exclude(pattern);
+        }
+        if let Some(rev) = &self.changed_since {
+            find_and_replace = find_and_replace.changed_since(rev);
+        }
+        if let Some(max_count) = self.max_count {
+            find_and_replace = find_and_replace.max_count(max_count);
+        }
+        if let Some(occurrence) = self.occurrence {
+            find_and_replace = find_and_replace.occurrence(occurrence);
+        }
+        if self.first_in_file {
+            find_and_replace = find_and_replace.first_in_file();
+        }
+        if let Some(lines) = &self.lines {
+            find_and_replace = find_and_replace.lines(lines.clone());
+        }
+        if let Some(between) = &self.between {
+            find_and_replace = find_and_replace.between(&between[0], &between[1]);
+        }
+        if let Some(scope) = self.scope {
+            find_and_replace = find_and_replace.scope(scope.into());
+        }
+        if let Some(template) = &self.insert_before {
+            find_and_replace = find_and_replace.insert_before(template);
+        }
+        if let Some(template) = &self.insert_after {
+            find_and_replace = find_and_replace.insert_after(template);
+        }
+        if let Some(max_total) = self.max_total {
+            find_and_replace = find_and_replace.max_total(max_total);
+        }
+        if let Some(rev) = &self.only_changed_lines {
+            find_and_replace =
+                find_and_replace.only_changed_lines(rev.as_deref().unwrap_or("HEAD"));
+        }
+
+        find_and_replace = if self.tui {
+            find_and_replace.tui()
+        } else if self.write {
+            find_and_replace.write()
+        } else if self.dry_run || self.check || self.searches_only() || self.stdin_content {
+            find_and_replace.dry_run()
+        } else if self.reads_paths_from_stdin() || self.reads_pattern_from_stdin() {
+            // Standard input is taken up by paths or the pattern, so
+            // ask the terminal.
+            ensure!(
+                PromptInput::open_tty().is_ok(),
+                "cannot use interactive prompt when reading from stdin without a terminal"
+            );
+            find_and_replace.prompt(PromptInput::Tty)
+        } else {
+            find_and_replace.prompt(PromptInput::Stdin)
+        };
+
+        Ok(find_and_replace)
+    }
+
+    fn case(&self) -> Case {
+        if self.case_sensitive {
+            Case::Sensitive
+        } else if self.ignore_case {
+            Case::Insensitive
+        } else {
+            Case::Smart
+        }
     }
 
-    fn search_paths(&self) -> Result<Cow<[PathBuf]>> {
+    /// Paths given on the command line or standard input.
+    fn given_paths(&self) -> Result<Cow<'_, [PathBuf]>> {
         if !self.paths.is_empty() {
             return Ok(Cow::from(&self.paths));
         }
@@ -312,11 +715,7 @@ impl Config {
         // there's input piped to the process.
         //
         // Otherwise, we just search the current directory.
-        let paths = if !atty::is(Stream::Stdin) {
-            ensure!(
-                !self.is_interactive(),
-                "cannot use interactive prompt when reading files from stdin"
-            );
+        let paths = if self.reads_paths_from_stdin() {
             let mut paths = vec![];
             for line in std::io::stdin().lock().lines() {
                 paths.push(PathBuf::from(line.unwrap()));
@@ -329,64 +728,24 @@ impl Config {
         Ok(Cow::from(paths))
     }
 
-    fn file_walker(&self) -> Result<WalkBuilder> {
-        let paths = self.search_paths()?;
-
-        let mut file_walker = WalkBuilder::new(&paths[0]);
-        for path in &paths[1..] {
-            file_walker.add(path);
-        }
-
-        // This is copied over from ripgrep, and seems to work well.
-        file_walker.threads(std::cmp::min(12, num_cpus::get()));
-
-        let should_ignore = !self.all_files;
-        let should_show_hidden = self.hidden || self.all_files;
-        file_walker
-            .hidden(!should_show_hidden)
-            .ignore(should_ignore)
-            .git_ignore(should_ignore)
-            .git_exclude(should_ignore)
-            .parents(should_ignore);
-
-        Ok(file_walker)
+    fn reads_paths_from_stdin(&self) -> bool {
+        self.paths.is_empty()
+            && !self.stdin_content
+            && !self.reads_pattern_from_stdin()
+            && !atty::is(Stream::Stdin)
     }
 
-    fn replacement_decider(&self) -> ReplacementDecider {
-        if self.write {
-            ReplacementDecider::constantly(ReplacementDecision::Accept)
-        } else if self.dry_run {
-            ReplacementDecider::constantly(ReplacementDecision::Ignore)
+    fn print_mode(&self) -> MatchPrintMode {
+        if self.files_with_matches {
+            MatchPrintMode::FilesWithMatches
+        } else if self.count {
+            MatchPrintMode::Count
+        } else if self.quiet {
+            MatchPrintMode::Silent
+        } else if self.compact {
+            MatchPrintMode::Compact
         } else {
-            ReplacementDecider::with_prompt()
-        }
-    }
-
-    fn searcher_builder(&self) -> SearcherBuilder {
-        let mut searcher_builder = SearcherBuilder::new();
-        searcher_builder
-            .binary_detection(BinaryDetection::quit(0x00))
-            .line_number(true)
-            .before_context(
-                self.before
-                    .or(self.context)
-                    .unwrap_or(DEFAULT_CONTEXT_LINES),
-            )
-            .after_context(self.after.or(self.context).unwrap_or(DEFAULT_CONTEXT_LINES));
-
-        searcher_builder
-    }
-
-    fn match_printer(&self) -> MatchPrinterBuilder {
-        MatchPrinterBuilder {
-            print_mode: if self.quiet {
-                MatchPrintMode::Silent
-            } else if self.compact {
-                MatchPrintMode::Compact
-            } else {
-                MatchPrintMode::Full
-            },
-            writes_enabled: self.write || self.is_interactive(),
+            MatchPrintMode::Full
         }
     }
 
@@ -403,356 +762,80 @@ impl Config {
             ColorPreference::Never => ColorChoice::Never,
         }
     }
-
-    /// Whether to run with an interactive prompt
-    fn is_interactive(&self) -> bool {
-        !self.dry_run && !self.write
-    }
 }
 
-struct FindAndReplacer {
-    config: Config,
-    file_walker: WalkBuilder,
-    path_matcher: PathMatcher,
-    match_printer: MatchPrinterBuilder,
-    replacer_factory: ReplacerFactory,
-    searcher_factory: RegexSearcherFactory,
-}
+/// Parse a `START:END` range of line numbers, where either end may
+/// be left out.
+fn parse_line_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| "expected a range like 10:200".to_string())?;
 
-const DEFAULT_CONTEXT_LINES: usize = 2;
-
-impl FindAndReplacer {
-    fn from_config(config: Config) -> Result<FindAndReplacer> {
-        let regex_matcher = Arc::new(config.regex_matcher()?);
-
-        // TODO: Confirm that template does not reference more capture groups than exist.
-        let replacer_factory = ReplacerFactory::new(
-            regex_matcher.clone(),
-            Arc::new(config.replace.to_owned()),
-            config.replacement_decider(),
-        );
-
-        let searcher_factory = RegexSearcherFactory::new(config.searcher_builder(), regex_matcher);
-
-        Ok(FindAndReplacer {
-            file_walker: config.file_walker()?,
-            path_matcher: config.path_matcher()?,
-            match_printer: config.match_printer(),
-            searcher_factory,
-            replacer_factory,
-
-            config,
-        })
-    }
-
-    fn run(&mut self) -> Result<()> {
-        // We can only use parallel mode if we're not using the interactive prompt.
-        if self.config.is_interactive() {
-            self.run_with_prompt()
+    let parse = |n: &str, default| {
+        if n.is_empty() {
+            Ok(default)
         } else {
-            self.run_parallel()
-        }
-    }
-
-    fn run_with_prompt(&mut self) -> Result<()> {
-        let stats = Arc::new(Statistics::new());
-        let start_time = Instant::now();
-
-        let mut searcher = self.searcher_factory.build();
-        let mut replacer = self.replacer_factory.build();
-
-        let mut writer = StandardStream::stdout(self.config.color_choice());
-        let mut match_printer = self.match_printer.build(&mut writer);
-
-        for dir_entry in self.file_walker.build() {
-            let _search_timer = stats.search_timer();
-
-            let path = match dir_entry {
-                Ok(ref entry) => {
-                    if !self.path_matcher.should_search(entry) {
-                        stats.visit_file(false);
-                        continue;
-                    }
-
-                    entry.path()
-                }
-
-                Err(err) => {
-                    eprintln!("error: {}", err);
-                    continue;
-                }
-            };
-
-            stats.visit_file(true);
-
-            let matches = match searcher.search_path(path) {
-                Ok(matches) => {
-                    // No futher processing required for empty matches.
-                    if matches.is_empty() {
-                        continue;
-                    }
-
-                    stats.add_matches(matches.len());
-                    matches
-                }
-
-                err => {
-                    eprintln!("search failed: {:?}", err);
-                    break;
-                }
-            };
-
-            let mut should_quit = false;
-            let num_replaced =
-                replacer.consume_matches(path, matches, &mut match_printer, &mut should_quit);
-
-            match num_replaced {
-                Ok(num) => {
-                    if num > 0 {
-                        stats.add_replacements(num);
-                    }
-                }
-                Err(err) => {
-                    eprintln!("{}: {}", path.display(), err);
-                    break;
-                }
-            }
-
-            if should_quit {
-                break;
-            }
-        }
-
-        stats.add_elapsed_wall_time(start_time.elapsed());
-        match_printer.display_footer(&stats)?;
-
-        if self.config.print_stats {
-            writeln!(&mut writer, "{}", stats)?;
+            n.parse::<u64>().map_err(|e| format!("{}: {}", n, e))
         }
+    };
 
-        Ok(())
+    let range = parse(start, 1)?..=parse(end, u64::MAX)?;
+    if *range.start() == 0 || range.is_empty() {
+        return Err(format!("{} is not a range of line numbers", s));
     }
 
-    fn run_parallel(&mut self) -> Result<()> {
-        let writer = BufferWriter::stdout(self.config.color_choice());
-        let stats = Arc::new(Statistics::new());
-        let start_time = Instant::now();
-
-        let file_walker = self.file_walker.build_parallel();
-        file_walker.run(|| {
-            let writer = &writer;
-            let path_matcher = &self.path_matcher;
-            let match_printer = &self.match_printer;
-            let stats = &stats;
-
-            let mut searcher = self.searcher_factory.build();
-            let mut replacer = self.replacer_factory.build();
-
-            Box::new(move |dir_entry| {
-                let _search_timer = stats.search_timer();
-
-                let path = match dir_entry {
-                    Ok(ref entry) => {
-                        if !path_matcher.should_search(entry) {
-                            // TODO: this counts directories
-                            stats.visit_file(false);
-                            return WalkState::Continue;
-                        }
-
-                        entry.path()
-                    }
-
-                    Err(err) => {
-                        eprintln!("error: {}", err);
-                        return WalkState::Continue;
-                    }
-                };
-
-                stats.visit_file(true);
-
-                let matches = match searcher.search_path(path) {
-                    Ok(matches) => {
-                        // No futher processing required for empty matches.
-                        if matches.is_empty() {
-                            return WalkState::Continue;
-                        }
-
-                        stats.add_matches(matches.len());
-                        matches
-                    }
-
-                    err => {
-                        eprintln!("search failed: {:?}", err);
-                        return WalkState::Quit;
-                    }
-                };
-
-                let mut buffer = writer.buffer();
-                let mut match_printer = match_printer.build(&mut buffer);
-
-                let mut should_quit = false;
-                let num_replaced =
-                    replacer.consume_matches(path, matches, &mut match_printer, &mut should_quit);
-
-                match num_replaced {
-                    Ok(num) => {
-                        if num > 0 {
-                            stats.add_replacements(num);
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("{}: {}", path.display(), err);
-                        return WalkState::Quit;
-                    }
-                }
-
-                if let Err(err) = writer.print(&buffer) {
-                    if err.kind() == io::ErrorKind::BrokenPipe {
-                        return WalkState::Quit;
-                    }
-                    eprintln!("{}: {}", path.display(), err);
-                }
-
-                if should_quit {
-                    WalkState::Quit
-                } else {
-                    WalkState::Continue
-                }
-            })
-        });
-
-        stats.add_elapsed_wall_time(start_time.elapsed());
-
-        let mut buffer = writer.buffer();
-        let mut match_printer = self.match_printer.build(&mut buffer);
-        match_printer.display_footer(&stats)?;
-
-        if self.config.print_stats {
-            writeln!(&mut buffer, "{}", stats)?;
-        }
-
-        if let Err(err) = writer.print(&buffer) {
-            if err.kind() != io::ErrorKind::BrokenPipe {
-                return Err(anyhow!(err));
-            }
-        }
-
-        Ok(())
-    }
+    Ok(range)
 }
 
-struct PathMatcher {
-    included_paths: Option<RegexSet>,
-    excluded_paths: RegexSet,
+fn is_stdin(path: &Option<PathBuf>) -> bool {
+    path.as_deref() == Some(Path::new("-"))
 }
 
-impl PathMatcher {
-    fn should_search(&self, dir_entry: &DirEntry) -> bool {
-        // Don't need to consider directories
-        let is_file = dir_entry.file_type().map_or(false, |it| it.is_file());
-
-        is_file && self.path_matches(dir_entry.path())
-    }
-
-    fn path_matches(&self, path: &Path) -> bool {
-        let path_str = path
-            .to_str()
-            .with_context(|| format!("Failed to interpret path name as UTF-8 string: {:?}", path))
-            .unwrap();
-
-        if let Some(included_paths) = &self.included_paths {
-            return included_paths.is_match(path_str);
-        }
+// Main entry point
+fn run_find_and_replace() -> Result<i32> {
+    let config = Config::parse_args()?;
+    let stats = if config.stdin_content {
+        ensure!(
+            config.paths.is_empty() && !config.reads_pattern_from_stdin(),
+            "standard input is already taken by --stdin-content"
+        );
 
-        !self.excluded_paths.is_match(path_str)
-    }
-}
+        let stdout = std::io::stdout();
+        config
+            .find_and_replace()?
+            .filter(std::io::stdin().lock(), BufWriter::new(stdout.lock()))?
+    } else {
+        config.find_and_replace()?.run()?
+    };
 
-// Main entry point
-fn run_find_and_replace() -> Result<()> {
-    let config = Config::parse();
-    let mut find_and_replacer = FindAndReplacer::from_config(config)?;
+    // Like grep, exit with 0 if anything matched, 1 if nothing did,
+    // and 2 on errors. --check is the other way around, as changes
+    // are what it's checking for, so matches which would be left as
+    // they are don't count.
+    let matched = if config.check {
+        stats.num_changes() > 0 || stats.num_path_matches() > 0
+    } else {
+        stats.num_matches() > 0 || stats.num_path_matches() > 0
+    };
+    let exit_code = if stats.num_errors() > 0 {
+        2
+    } else if matched != config.check {
+        0
+    } else {
+        1
+    };
 
-    find_and_replacer.run()
+    Ok(exit_code)
 }
 
 fn main() {
     let exit_code = match run_find_and_replace() {
         Err(e) => {
             eprintln!("{:?}", e);
-            1
+            2
         }
-        Ok(()) => 0,
+        Ok(exit_code) => exit_code,
     };
 
     std::process::exit(exit_code);
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    mod path_matcher {
-        use super::*;
-
-        fn as_regex_set(v: Vec<&str>) -> RegexSet {
-            let escaped = v.iter().map(|r| regex::escape(r));
-            RegexSet::new(escaped).unwrap()
-        }
-
-        #[test]
-        fn test_empty_included_set() {
-            let disallow_list: Vec<&str> = vec![];
-
-            let matcher = PathMatcher {
-                included_paths: None,
-                excluded_paths: as_regex_set(disallow_list),
-            };
-
-            assert_eq!(matcher.path_matches(&Path::new("foo")), true);
-        }
-
-        #[test]
-        fn test_included_set() {
-            let allow_list: Vec<&str> = vec!["foo", "bar"];
-            let disallow_list: Vec<&str> = vec![];
-
-            let matcher = PathMatcher {
-                included_paths: Some(as_regex_set(allow_list)),
-                excluded_paths: as_regex_set(disallow_list),
-            };
-
-            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), true);
-            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), true);
-            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), false);
-        }
-
-        #[test]
-        fn test_excluded_set() {
-            let disallow_list = vec!["foo", "bar"];
-            let matcher = PathMatcher {
-                included_paths: None,
-                excluded_paths: as_regex_set(disallow_list),
-            };
-
-            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), false);
-            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), false);
-            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), true);
-        }
-
-        // Inclusion should take precedence
-        #[test]
-        fn test_included_and_excluded_set() {
-            let allow_list = vec!["foo", "bar"];
-            let disallow_list = vec!["foo", "bar"];
-
-            let matcher = PathMatcher {
-                included_paths: Some(as_regex_set(allow_list)),
-                excluded_paths: as_regex_set(disallow_list),
-            };
-
-            assert_eq!(matcher.path_matches(&Path::new("foo.rs")), true);
-            assert_eq!(matcher.path_matches(&Path::new("bar.rs")), true);
-            assert_eq!(matcher.path_matches(&Path::new("baz.rs")), false);
-        }
-    }
-}
@@ -0,0 +1,112 @@
+use grep::matcher::{Captures, LineMatchKind, LineTerminator, Match, Matcher, NoError};
+use grep::regex::{RegexCaptures, RegexMatcher};
+
+use crate::structural::{StructuralCaptures, StructuralMatcher};
+
+/// Matches FIND, as either a regular expression or a structural
+/// pattern.
+///
+/// A tree-sitter query isn't one of these, as it needs the whole
+/// syntax tree rather than text; see `search::Searcher`.
+///
+/// Only one is built per run and shared between threads, so its size
+/// doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum PatternMatcher {
+    Regex(RegexMatcher),
+    Structural(StructuralMatcher),
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternCaptures {
+    Regex(RegexCaptures),
+    Structural(StructuralCaptures),
+}
+
+impl PatternMatcher {
+    /// Whether matches can span more than one line.
+    pub fn is_multi_line(&self) -> bool {
+        matches!(self, PatternMatcher::Structural(_))
+    }
+}
+
+impl Captures for PatternCaptures {
+    fn len(&self) -> usize {
+        match self {
+            PatternCaptures::Regex(caps) => caps.len(),
+            PatternCaptures::Structural(caps) => caps.len(),
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<Match> {
+        match self {
+            PatternCaptures::Regex(caps) => caps.get(i),
+            PatternCaptures::Structural(caps) => caps.get(i),
+        }
+    }
+}
+
+impl Matcher for PatternMatcher {
+    type Captures = PatternCaptures;
+    type Error = NoError;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, NoError> {
+        match self {
+            PatternMatcher::Regex(m) => m.find_at(haystack, at),
+            PatternMatcher::Structural(m) => m.find_at(haystack, at),
+        }
+    }
+
+    fn new_captures(&self) -> Result<PatternCaptures, NoError> {
+        match self {
+            PatternMatcher::Regex(m) => m.new_captures().map(PatternCaptures::Regex),
+            PatternMatcher::Structural(m) => m.new_captures().map(PatternCaptures::Structural),
+        }
+    }
+
+    fn capture_count(&self) -> usize {
+        match self {
+            PatternMatcher::Regex(m) => m.capture_count(),
+            PatternMatcher::Structural(m) => m.capture_count(),
+        }
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        match self {
+            PatternMatcher::Regex(m) => m.capture_index(name),
+            PatternMatcher::Structural(m) => m.capture_index(name),
+        }
+    }
+
+    fn captures_at(
+        &self,
+        haystack: &[u8],
+        at: usize,
+        caps: &mut PatternCaptures,
+    ) -> Result<bool, NoError> {
+        match (self, caps) {
+            (PatternMatcher::Regex(m), PatternCaptures::Regex(caps)) => {
+                m.captures_at(haystack, at, caps)
+            }
+            (PatternMatcher::Structural(m), PatternCaptures::Structural(caps)) => {
+                m.captures_at(haystack, at, caps)
+            }
+            _ => unreachable!("captures from a different matcher"),
+        }
+    }
+
+    fn line_terminator(&self) -> Option<LineTerminator> {
+        match self {
+            PatternMatcher::Regex(m) => m.line_terminator(),
+            PatternMatcher::Structural(m) => m.line_terminator(),
+        }
+    }
+
+    fn find_candidate_line(&self, haystack: &[u8]) -> Result<Option<LineMatchKind>, NoError> {
+        match self {
+            PatternMatcher::Regex(m) => m.find_candidate_line(haystack),
+            PatternMatcher::Structural(m) => m.find_candidate_line(haystack),
+        }
+    }
+}
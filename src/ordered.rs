@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Process paths on worker threads, handing each result back to the
+/// calling thread in the same order the paths were produced.
+///
+/// `walk` runs on its own thread and passes each path to the given
+/// callback, which returns `false` once processing has stopped. Every
+/// worker thread builds its own state with `make_worker`. `consume` is
+/// called on the current thread and returns `false` to stop early.
+///
+/// Workers run ahead of `consume`, but at most `window` results are
+/// held at once so that memory stays bounded.
+pub fn process_in_order<T, Walk, MakeWorker, Worker, Consume>(
+    num_threads: usize,
+    window: usize,
+    walk: Walk,
+    make_worker: MakeWorker,
+    mut consume: Consume,
+) where
+    T: Send,
+    Walk: FnOnce(&mut dyn FnMut(PathBuf) -> bool) + Send,
+    MakeWorker: Fn() -> Worker + Sync,
+    Worker: FnMut(&Path) -> T,
+    Consume: FnMut(PathBuf, T) -> bool,
+{
+    // Each path gets its own single-use result channel. Receivers are
+    // queued in walk order, so waiting on them in turn restores the
+    // order no matter which worker finishes first.
+    let (order_tx, order_rx) = mpsc::sync_channel::<(PathBuf, Receiver<T>)>(window);
+    let (job_tx, job_rx) = mpsc::channel::<(PathBuf, SyncSender<T>)>();
+    let job_rx = Mutex::new(job_rx);
+
+    thread::scope(|scope| {
+        for _ in 0..num_threads.max(1) {
+            let job_rx = &job_rx;
+            let make_worker = &make_worker;
+
+            scope.spawn(move || {
+                let mut worker = make_worker();
+                loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (path, result_tx) = match job {
+                        Ok(job) => job,
+                        // Walk has finished, or was stopped.
+                        Err(_) => break,
+                    };
+
+                    // The receiver is gone if we've stopped early.
+                    let _ = result_tx.send(worker(&path));
+                }
+            });
+        }
+
+        scope.spawn(move || {
+            walk(&mut |path| {
+                let (result_tx, result_rx) = mpsc::sync_channel(1);
+                order_tx.send((path.clone(), result_rx)).is_ok()
+                    && job_tx.send((path, result_tx)).is_ok()
+            });
+        });
+
+        for (path, result_rx) in order_rx {
+            let result = match result_rx.recv() {
+                Ok(result) => result,
+                // Worker panicked.
+                Err(_) => continue,
+            };
+
+            if !consume(path, result) {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_results_in_walk_order() {
+        let paths: Vec<PathBuf> = (0..50).map(|i| PathBuf::from(i.to_string())).collect();
+
+        let mut results = vec![];
+        process_in_order(
+            4,
+            8,
+            |emit| {
+                for path in paths.clone() {
+                    if !emit(path) {
+                        break;
+                    }
+                }
+            },
+            || {
+                |path: &Path| {
+                    // Finish out of order.
+                    let i: u64 = path.to_str().unwrap().parse().unwrap();
+                    thread::sleep(Duration::from_millis((50 - i) % 7));
+                    i
+                }
+            },
+            |_path, i| {
+                results.push(i);
+                true
+            },
+        );
+
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stop_early() {
+        let mut results = vec![];
+        process_in_order(
+            2,
+            2,
+            |emit| {
+                for i in 0.. {
+                    if !emit(PathBuf::from(i.to_string())) {
+                        break;
+                    }
+                }
+            },
+            || |path: &Path| path.to_owned(),
+            |path, _| {
+                results.push(path);
+                results.len() < 3
+            },
+        );
+
+        assert_eq!(results.len(), 3);
+    }
+}
@@ -4,18 +4,25 @@ use std::path::Path;
 use anyhow::Result;
 use termcolor::{ColorSpec, WriteColor};
 
+use crate::event::{Event, EventSink};
 use crate::search::Match;
 use crate::Statistics;
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub enum MatchPrintMode {
     Silent,
     Compact,
     Full,
+    /// Only the paths of files with matches.
+    FilesWithMatches,
+    /// Paths of files with matches and how many each has.
+    Count,
 }
 
 pub struct MatchPrinterBuilder {
     pub print_mode: MatchPrintMode,
+    /// Print matches without replacements.
+    pub search_only: bool,
     pub writes_enabled: bool,
 }
 
@@ -24,6 +31,7 @@ impl MatchPrinterBuilder {
         MatchPrinter {
             writer,
             print_mode: self.print_mode,
+            search_only: self.search_only,
             writes_enabled: self.writes_enabled,
             last_line_num: None,
         }
@@ -34,16 +42,25 @@ pub struct MatchPrinter<'a, W: WriteColor> {
     writer: &'a mut W,
 
     print_mode: MatchPrintMode,
+    search_only: bool,
     writes_enabled: bool,
     last_line_num: Option<u64>,
 }
 
 impl<'a, W: WriteColor> MatchPrinter<'a, W> {
-    pub fn display_header(&mut self, path: &Path, num_matches: usize) -> Result<()> {
+    fn display_header(&mut self, path: &Path, num_matches: usize) -> Result<()> {
         match self.print_mode {
             MatchPrintMode::Silent => Ok(()),
             MatchPrintMode::Compact => Ok(()),
             MatchPrintMode::Full => self.display_header_full(path, num_matches),
+            MatchPrintMode::FilesWithMatches => {
+                writeln!(&mut self.writer, "{}", path.display())?;
+                Ok(())
+            }
+            MatchPrintMode::Count => {
+                writeln!(&mut self.writer, "{}:{}", path.display(), num_matches)?;
+                Ok(())
+            }
         }
     }
 
@@ -64,16 +81,26 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
         Ok(())
     }
 
-    pub fn display_match(
+    /// Display a match and its replacement, or just the match when
+    /// only searching.
+    ///
+    /// `swaps` lists each `(matched text, replacement)` exchange made
+    /// on the line when using `--swap`, and is otherwise empty.
+    fn display_match(
         &mut self,
         path: &Path,
         search_match: &Match,
         replacement: &str,
+        swaps: &[(String, String)],
     ) -> Result<()> {
         match self.print_mode {
-            MatchPrintMode::Silent => Ok(()),
-            MatchPrintMode::Compact => self.display_match_compact(path, search_match, replacement),
-            MatchPrintMode::Full => self.display_match_full(search_match, replacement),
+            MatchPrintMode::Silent | MatchPrintMode::FilesWithMatches | MatchPrintMode::Count => {
+                Ok(())
+            }
+            MatchPrintMode::Compact => {
+                self.display_match_compact(path, search_match, replacement, swaps)
+            }
+            MatchPrintMode::Full => self.display_match_full(search_match, replacement, swaps),
         }
     }
 
@@ -83,6 +110,7 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
         path: &Path,
         search_match: &Match,
         replacement: &str,
+        swaps: &[(String, String)],
     ) -> Result<()> {
         let path = path.display();
 
@@ -90,16 +118,33 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
             write!(&mut self.writer, "{}:{}:{}", path, line.0, line.1)?;
         }
 
-        write!(
-            &mut self.writer,
-            "\x1B[31m{}:{}-{}\x1B[0m",
-            path, search_match.line.0, search_match.line.1
-        )?;
-        write!(
-            &mut self.writer,
-            "\x1B[32m{}:{}+{}\x1B[0m",
-            path, search_match.line.0, replacement
-        )?;
+        let marker = self.match_marker();
+        for (line_num, line) in numbered_lines(search_match.line.0, &search_match.line.1) {
+            write!(
+                &mut self.writer,
+                "\x1B[31m{}:{}{}{}\x1B[0m",
+                path, line_num, marker, line
+            )?;
+        }
+        if !self.search_only {
+            for (line_num, line) in numbered_lines(search_match.line.0, replacement) {
+                write!(
+                    &mut self.writer,
+                    "\x1B[32m{}:{}+{}\x1B[0m",
+                    path, line_num, line
+                )?;
+            }
+        }
+
+        if !swaps.is_empty() {
+            writeln!(
+                &mut self.writer,
+                "{}:{}~{}",
+                path,
+                search_match.line.0,
+                format_swaps(swaps)
+            )?;
+        }
 
         for line in &search_match.context_post {
             write!(&mut self.writer, "{}:{}:{}", path, line.0, line.1)?;
@@ -109,7 +154,12 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
     }
 
     #[inline]
-    fn display_match_full(&mut self, m: &Match, replacement: &str) -> Result<()> {
+    fn display_match_full(
+        &mut self,
+        m: &Match,
+        replacement: &str,
+        swaps: &[(String, String)],
+    ) -> Result<()> {
         let has_line_break = self
             .last_line_num
             .map(|last_line_num| {
@@ -131,16 +181,23 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
 
         // TODO: Highlight matching part of line
         // TODO: Disable colors when not atty
-        write!(
-            &mut self.writer,
-            "\x1B[31m-{:4} {}\x1B[0m",
-            m.line.0, m.line.1
-        )?;
-        write!(
-            &mut self.writer,
-            "\x1B[32m+{:4} {}\x1B[0m",
-            m.line.0, replacement
-        )?;
+        let marker = self.match_marker();
+        for (line_num, line) in numbered_lines(m.line.0, &m.line.1) {
+            write!(
+                &mut self.writer,
+                "\x1B[31m{}{:4} {}\x1B[0m",
+                marker, line_num, line
+            )?;
+        }
+        if !self.search_only {
+            for (line_num, line) in numbered_lines(m.line.0, replacement) {
+                write!(&mut self.writer, "\x1B[32m+{:4} {}\x1B[0m", line_num, line)?;
+            }
+        }
+
+        if !swaps.is_empty() {
+            writeln!(&mut self.writer, "      ({})", format_swaps(swaps))?;
+        }
 
         for line in &m.context_post {
             write!(&mut self.writer, " {:4} {}", line.0, line.1)?;
@@ -150,14 +207,59 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
         Ok(())
     }
 
+    /// Marks matched lines: as removed when there's a replacement to
+    /// show, or as found when only searching.
+    fn match_marker(&self) -> char {
+        if self.search_only {
+            '>'
+        } else {
+            '-'
+        }
+    }
+
+    fn display_rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        match self.print_mode {
+            MatchPrintMode::Silent | MatchPrintMode::FilesWithMatches | MatchPrintMode::Count => {
+                Ok(())
+            }
+            MatchPrintMode::Compact => {
+                writeln!(&mut self.writer, "{} -> {}", from.display(), to.display())?;
+                Ok(())
+            }
+            MatchPrintMode::Full => {
+                writeln!(&mut self.writer, "\x1B[31m- {}\x1B[0m", from.display())?;
+                writeln!(&mut self.writer, "\x1B[32m+ {}\x1B[0m", to.display())?;
+                Ok(())
+            }
+        }
+    }
+
     pub fn display_footer(&mut self, stats: &Statistics) -> Result<()> {
         match self.print_mode {
-            MatchPrintMode::Silent => Ok(()),
-            MatchPrintMode::Compact => Ok(()),
+            MatchPrintMode::Full if self.search_only => self.display_search_footer_full(stats),
             MatchPrintMode::Full => self.display_footer_full(stats),
+            _ => Ok(()),
         }
     }
 
+    #[inline]
+    fn display_search_footer_full(&mut self, stats: &Statistics) -> Result<()> {
+        writeln!(
+            &mut self.writer,
+            "All done. Found {} match{} in {} file{}",
+            stats.num_matches(),
+            if stats.num_matches() == 1 { "" } else { "es" },
+            stats.files_with_matches(),
+            if stats.files_with_matches() == 1 {
+                ""
+            } else {
+                "s"
+            },
+        )?;
+
+        Ok(())
+    }
+
     #[inline]
     fn display_footer_full(&mut self, stats: &Statistics) -> Result<()> {
         writeln!(
@@ -167,6 +269,15 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
             stats.num_matches(),
         )?;
 
+        if stats.num_path_matches() > 0 {
+            writeln!(
+                &mut self.writer,
+                "Renamed {} of {} paths",
+                stats.num_renames(),
+                stats.num_path_matches(),
+            )?;
+        }
+
         if !self.writes_enabled {
             writeln!(
                 &mut self.writer,
@@ -177,3 +288,52 @@ impl<'a, W: WriteColor> MatchPrinter<'a, W> {
         Ok(())
     }
 }
+
+impl<'a, W: WriteColor> EventSink for MatchPrinter<'a, W> {
+    fn handle(&mut self, event: Event<'_>) -> Result<()> {
+        match event {
+            Event::File { path, num_matches } => self.display_header(path, num_matches),
+            Event::Match {
+                path,
+                search_match,
+                replacement,
+                swaps,
+            } => self.display_match(path, search_match, replacement, swaps),
+            Event::Rename { from, to } => self.display_rename(from, to),
+            Event::Replaced { .. } | Event::Renamed { .. } => Ok(()),
+            // Kept out of the match output, which may be piped.
+            Event::Skipped { path, reason } => {
+                eprintln!("{}: {}", path.display(), reason);
+                Ok(())
+            }
+            Event::Error {
+                path: Some(path),
+                message,
+            } => {
+                eprintln!("{}: {}", path.display(), message);
+                Ok(())
+            }
+            Event::Error {
+                path: None,
+                message,
+            } => {
+                eprintln!("error: {}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lines of `text` numbered from `first`, as a match or replacement
+/// may span several lines.
+fn numbered_lines(first: u64, text: &str) -> impl Iterator<Item = (u64, &str)> {
+    (first..).zip(text.split_inclusive('\n'))
+}
+
+fn format_swaps(swaps: &[(String, String)]) -> String {
+    swaps
+        .iter()
+        .map(|(from, to)| format!("{} -> {}", from, to))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,44 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::git;
+
+/// How accepted path renames are carried out.
+#[derive(Debug, Copy, Clone)]
+pub enum PathRenamer {
+    /// Move the path directly on the file system.
+    Filesystem,
+    /// Use `git mv`, so the rename is staged in the repository.
+    Git,
+}
+
+impl PathRenamer {
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        match self {
+            Self::Filesystem => std::fs::rename(from, to)
+                .with_context(|| format!("failed to rename to {}", to.display())),
+            Self::Git => Self::git_mv(from, to),
+        }
+    }
+
+    fn git_mv(from: &Path, to: &Path) -> Result<()> {
+        // Renames only ever change the final path component, so run
+        // from the parent directory to find the right repository.
+        let parent = match from.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let from_name = from
+            .file_name()
+            .context("cannot rename path without file name")?;
+        let to_name = to
+            .file_name()
+            .context("cannot rename path without file name")?;
+        git::git_in(parent, [OsStr::new("mv"), from_name, to_name])?;
+
+        Ok(())
+    }
+}
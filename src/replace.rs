@@ -1,56 +1,197 @@
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
-use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use atty::Stream;
 use grep::matcher::{Captures, Matcher};
-use grep::regex::RegexMatcher;
+use ratatui::crossterm::event::{self, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal;
 use tempfile::NamedTempFile;
-use termcolor::WriteColor;
-use text_io::read;
 
-use crate::printer::MatchPrinter;
-use crate::search::Match;
+use crate::editor;
+use crate::event::{Decision, Event, EventSink, Proposal};
+use crate::matcher::PatternMatcher;
+use crate::rename::PathRenamer;
+use crate::search::{Line, Match};
 
 struct MatchReplacement<'a> {
     search_match: Match,
     replacement: Cow<'a, str>,
 }
 
+/// Two strings which are exchanged for one another, see `--swap`.
+#[derive(Debug, Clone)]
+pub struct Swap {
+    pub left: String,
+    pub right: String,
+}
+
+impl Swap {
+    const LEFT_GROUP: &'static str = "fnr_swap_left";
+    const RIGHT_GROUP: &'static str = "fnr_swap_right";
+
+    /// Build a single pattern matching either side of the swap, so
+    /// that both directions are replaced in one pass.
+    ///
+    /// Each side is inserted as is in place of the other, so both are
+    /// matched literally rather than as regular expressions. The
+    /// longer side comes first, as the first alternative to match
+    /// wins and one side may start with the other.
+    pub fn pattern(&self) -> String {
+        let left = format!("(?P<{}>{})", Self::LEFT_GROUP, regex::escape(&self.left));
+        let right = format!("(?P<{}>{})", Self::RIGHT_GROUP, regex::escape(&self.right));
+        if self.right.len() > self.left.len() {
+            format!("{}|{}", right, left)
+        } else {
+            format!("{}|{}", left, right)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplacementTemplate {
+    /// Template which may reference capture groups, e.g. `$1`.
+    Captures(Arc<String>),
+    /// Replace matches of either side of the swap with the other.
+    Swap(Arc<Swap>),
+    /// Leave matches as they are, to only search or edit the lines
+    /// around them.
+    Unchanged,
+}
+
+/// Edits to the lines a match is on, besides replacing within them.
+#[derive(Debug, Clone, Default)]
+pub struct LineEdits {
+    /// Drop the lines entirely.
+    pub delete: bool,
+    /// Templates for a line to insert before or after, which may
+    /// reference capture groups like the replacement.
+    pub insert_before: Option<Arc<String>>,
+    pub insert_after: Option<Arc<String>>,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum ReplacementDecision {
     Accept,
     Ignore,
+    /// Edit the replacement in `$VISUAL` or `$EDITOR` first.
     Edit,
+    /// Ignore this match and stop processing any more.
     Terminate,
+    /// Revisit the previous match in this file.
+    Undo,
+    /// Ignore this match and the rest of the file without showing them.
+    SkipFile,
+    /// Show more lines surrounding the match, then decide again.
+    MoreContext,
+}
+
+/// Where answers to the interactive prompt are read from.
+#[derive(Debug, Copy, Clone)]
+pub enum PromptInput {
+    Stdin,
+    /// The controlling terminal, used when standard input is already
+    /// taken by something else, such as a list of paths.
+    Tty,
+}
+
+impl PromptInput {
+    const TTY_PATH: &'static str = "/dev/tty";
+
+    pub fn open_tty() -> std::io::Result<File> {
+        File::open(Self::TTY_PATH)
+    }
+
+    /// Read a line of input without its line terminator.
+    ///
+    /// The same buffered reader is used for the whole run, so that
+    /// anything typed ahead isn't lost.
+    fn read_line(&self) -> std::io::Result<String> {
+        match self {
+            Self::Stdin => read_answer(&mut std::io::stdin().lock()),
+            Self::Tty => {
+                static TTY: OnceLock<Mutex<BufReader<File>>> = OnceLock::new();
+
+                let tty = match TTY.get() {
+                    Some(tty) => tty,
+                    None => {
+                        let tty = Mutex::new(BufReader::new(Self::open_tty()?));
+                        TTY.get_or_init(|| tty)
+                    }
+                };
+                read_answer(&mut *tty.lock().unwrap())
+            }
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        match self {
+            Self::Stdin => atty::is(Stream::Stdin),
+            Self::Tty => true,
+        }
+    }
+}
+
+/// Decides on each proposed change in place of the interactive prompt.
+pub type DecisionCallback = dyn FnMut(Proposal<'_>) -> Decision + Send;
+
+impl From<Decision> for ReplacementDecision {
+    fn from(decision: Decision) -> ReplacementDecision {
+        match decision {
+            Decision::Accept => ReplacementDecision::Accept,
+            Decision::Ignore => ReplacementDecision::Ignore,
+            Decision::Terminate => ReplacementDecision::Terminate,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub enum ReplacementDecider {
     Constantly(ReplacementDecision),
+    Callback(Arc<Mutex<Box<DecisionCallback>>>),
     WithPrompt {
+        input: PromptInput,
+        /// Applies to the remaining matches in the current file.
         local_decision: Option<ReplacementDecision>,
+        /// Applies to the remaining matches in every file.
+        global_decision: Option<ReplacementDecision>,
+        /// Skip matches until one whose line contains this text.
+        seek: Option<String>,
     },
 }
 
 pub struct ReplacerFactory {
-    regex_matcher: Arc<RegexMatcher>,
-    replacement_template: Arc<String>,
+    regex_matcher: Option<Arc<PatternMatcher>>,
+    replacement_template: ReplacementTemplate,
     replacement_decider: ReplacementDecider,
+    occurrence: Option<usize>,
+    line_edits: LineEdits,
 }
 
 impl ReplacerFactory {
+    /// Replacers rewrite every match on a line, or only the
+    /// `occurrence`th one, counting from 1, then apply `line_edits`.
+    ///
+    /// Without `regex_matcher`, every match must come with its
+    /// replacement from the searcher, as with tree-sitter queries.
     pub fn new(
-        regex_matcher: Arc<RegexMatcher>,
-        replacement_template: Arc<String>,
+        regex_matcher: Option<Arc<PatternMatcher>>,
+        replacement_template: ReplacementTemplate,
         replacement_decider: ReplacementDecider,
+        occurrence: Option<usize>,
+        line_edits: LineEdits,
     ) -> ReplacerFactory {
         ReplacerFactory {
             regex_matcher,
             replacement_template,
             replacement_decider,
+            occurrence,
+            line_edits,
         }
     }
 
@@ -62,6 +203,10 @@ impl ReplacerFactory {
 
             // This one isn't but is small.
             replacement_decider: self.replacement_decider.clone(),
+
+            occurrence: self.occurrence,
+            line_edits: self.line_edits.clone(),
+            num_changes: 0,
         }
     }
 }
@@ -71,36 +216,106 @@ impl ReplacementDecider {
         ReplacementDecider::Constantly(decision)
     }
 
-    pub fn with_prompt() -> ReplacementDecider {
+    pub fn with_prompt(input: PromptInput) -> ReplacementDecider {
         ReplacementDecider::WithPrompt {
+            input,
             local_decision: None,
+            global_decision: None,
+            seek: None,
         }
     }
 
-    fn decide(&mut self) -> ReplacementDecision {
+    fn decide(&mut self, proposal: Proposal<'_>) -> ReplacementDecision {
         match self {
             Self::Constantly(decision) => *decision,
+            Self::Callback(callback) => (callback.lock().unwrap())(proposal).into(),
             Self::WithPrompt {
+                input,
                 ref mut local_decision,
-            } => local_decision
-                .unwrap_or_else(|| ReplacementDecider::prompt_for_decision(local_decision)),
+                ref mut global_decision,
+                ref mut seek,
+            } => global_decision.or(*local_decision).unwrap_or_else(|| {
+                ReplacementDecider::prompt_for_decision(
+                    *input,
+                    local_decision,
+                    global_decision,
+                    seek,
+                )
+            }),
+        }
+    }
+
+    /// Whether a match on `line` should be passed over without being
+    /// shown, because we're looking for a later match.
+    fn is_seeking_past(&mut self, line: &str) -> bool {
+        match self {
+            Self::Constantly(_) | Self::Callback(_) => false,
+            Self::WithPrompt { seek, .. } => match seek {
+                Some(text) if line.contains(text.as_str()) => {
+                    *seek = None;
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            },
+        }
+    }
+
+    fn input(&self) -> PromptInput {
+        match self {
+            Self::Constantly(_) | Self::Callback(_) => PromptInput::Stdin,
+            Self::WithPrompt { input, .. } => *input,
+        }
+    }
+
+    /// Stop looking for the text searched for with `/`, returning it
+    /// if it was never found.
+    fn end_seek(&mut self) -> Option<String> {
+        match self {
+            Self::Constantly(_) | Self::Callback(_) => None,
+            Self::WithPrompt { seek, .. } => seek.take(),
+        }
+    }
+
+    /// Tell the user at the prompt what happened with their decision.
+    fn notify(&self, message: &str) {
+        if let Self::WithPrompt { .. } = self {
+            println!("{}", message);
         }
     }
 
     fn reset(&mut self) {
         match self {
-            Self::Constantly(_) => (),
-            Self::WithPrompt { local_decision } => {
+            Self::Constantly(_) | Self::Callback(_) => (),
+            Self::WithPrompt { local_decision, .. } => {
                 *local_decision = None;
             }
         }
     }
 
     fn prompt_for_decision(
+        input: PromptInput,
         local_decision: &mut Option<ReplacementDecision>,
+        global_decision: &mut Option<ReplacementDecision>,
+        seek: &mut Option<String>,
     ) -> ReplacementDecision {
         loop {
-            let line = read_input("Stage this replacement [y,n,q,a,e,d,?] ").unwrap();
+            let line = match read_key("Stage this replacement [y,n,q,a,d,A,D,s,u,m,e,/,?] ", input)
+            {
+                Ok(line) => line,
+                // Interrupted with Ctrl-C, out of answers, or they
+                // can't be read, so stop as with `q`.
+                Err(err) => {
+                    let is_expected = matches!(
+                        err.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof
+                    );
+                    if !is_expected {
+                        eprintln!("failed to read answer: {}", err);
+                    }
+                    return ReplacementDecision::Terminate;
+                }
+            };
 
             return match line.as_str() {
                 "y" => ReplacementDecision::Accept,
@@ -114,10 +329,25 @@ impl ReplacementDecider {
                     *local_decision = Some(ReplacementDecision::Ignore);
                     ReplacementDecision::Ignore
                 }
+                "A" => {
+                    *global_decision = Some(ReplacementDecision::Accept);
+                    ReplacementDecision::Accept
+                }
+                "D" => {
+                    *global_decision = Some(ReplacementDecision::Ignore);
+                    ReplacementDecision::Ignore
+                }
+                "s" => ReplacementDecision::SkipFile,
+                "u" => ReplacementDecision::Undo,
+                "m" => ReplacementDecision::MoreContext,
 
-                // TODO: support opening editor at point
                 "e" => ReplacementDecision::Edit,
 
+                _ if line.len() > 1 && line.starts_with('/') => {
+                    *seek = Some(line[1..].to_owned());
+                    ReplacementDecision::Ignore
+                }
+
                 _ => {
                     println!(
                         "\x1B[31m
@@ -126,7 +356,13 @@ n - do not replace this line
 q - quit; do not replace this line or any remaining ones
 a - replace this line and all remaining ones in this file
 d - do not replace this line nor any remaining ones in this file
-e - edit this replacement
+A - replace this line and all remaining ones in all files
+D - do not replace this line nor any remaining ones in any file
+s - skip the rest of this file
+u - undo the previous decision in this file
+m - show more lines around this match
+e - edit this replacement in $VISUAL or $EDITOR
+/ - search for the next match whose line contains the given text
 ? - show help
 \x1B[0m"
                     );
@@ -138,72 +374,354 @@ e - edit this replacement
 }
 
 pub struct Replacer {
-    regex_matcher: Arc<RegexMatcher>,
-    replacement_template: Arc<String>,
+    regex_matcher: Option<Arc<PatternMatcher>>,
+    replacement_template: ReplacementTemplate,
     replacement_decider: ReplacementDecider,
+    occurrence: Option<usize>,
+    line_edits: LineEdits,
+
+    /// Matches considered whose replacement differs from the original,
+    /// whether or not it was accepted.
+    num_changes: usize,
 }
 
 impl Replacer {
-    pub fn consume_matches<W: WriteColor>(
+    fn matcher(&self) -> &PatternMatcher {
+        self.regex_matcher
+            .as_deref()
+            .expect("matches without a pattern come with their replacement")
+    }
+
+    /// How many matches would have changed since this was last called.
+    pub fn take_num_changes(&mut self) -> usize {
+        std::mem::take(&mut self.num_changes)
+    }
+
+    pub fn consume_matches(
         &mut self,
         path: &Path,
         matches: Vec<Match>,
-        match_printer: &mut MatchPrinter<W>,
+        events: &mut dyn EventSink,
         should_quit: &mut bool,
     ) -> Result<usize> {
         if matches.is_empty() {
             return Ok(0);
         }
 
-        match_printer.display_header(path, matches.len())?;
+        events.handle(Event::File {
+            path,
+            num_matches: matches.len(),
+        })?;
 
         self.replacement_decider.reset();
 
-        let mut replacement_list = Vec::with_capacity(matches.len());
-        for m in matches.into_iter() {
-            let replacement = self.replace_with_captures(&m.line.1)?;
-            match_printer.display_match(path, &m, &replacement)?;
+        // Decisions are kept by index rather than consumed as we go, so
+        // that they can be undone.
+        let mut decisions: Vec<Option<String>> = Vec::with_capacity(matches.len());
+        let mut extra_context = 0;
+        let mut num_considered = 0;
+        while decisions.len() < matches.len() {
+            let m = &matches[decisions.len()];
+            if self.replacement_decider.is_seeking_past(&m.line.1) {
+                decisions.push(None);
+                continue;
+            }
 
-            let match_replacement = match self.replacement_decider.decide() {
-                ReplacementDecision::Accept => MatchReplacement {
-                    search_match: m,
-                    replacement: replacement.into(),
-                },
-                ReplacementDecision::Ignore => continue,
-                ReplacementDecision::Edit => {
-                    let mut line = read_input("Replace with [^D to skip] ")?;
-                    if line.is_empty() {
-                        println!("... skipped ...");
-                        continue;
-                    }
+            let mut swaps = vec![];
+            let replacement = self.replace_match(m, &mut swaps)?;
+            // Undo and more context show a match again.
+            if decisions.len() == num_considered {
+                num_considered += 1;
+                if replacement != m.line.1 {
+                    self.num_changes += 1;
+                }
+            }
+
+            let wider;
+            let shown = if extra_context > 0 {
+                wider = with_more_context(path, m, extra_context)?;
+                &wider
+            } else {
+                m
+            };
+            events.handle(Event::Match {
+                path,
+                search_match: shown,
+                replacement: &replacement,
+                swaps: &swaps,
+            })?;
 
-                    line.push('\n');
-                    match_printer.display_match(path, &m, &line)?;
-                    println!("--");
-                    MatchReplacement {
-                        search_match: m,
-                        replacement: line.into(),
+            let proposal = Proposal::Replace {
+                path,
+                search_match: m,
+                replacement: &replacement,
+            };
+            let decision = match self.replacement_decider.decide(proposal) {
+                ReplacementDecision::Accept => Some(replacement),
+                ReplacementDecision::Ignore => None,
+                ReplacementDecision::Edit => {
+                    let input = self.replacement_decider.input();
+                    match editor::edit_replacement(path, m, &replacement, input) {
+                        Ok(Some(edited)) => {
+                            events.handle(Event::Match {
+                                path,
+                                search_match: m,
+                                replacement: &edited,
+                                swaps: &[],
+                            })?;
+                            self.replacement_decider.notify("--");
+                            Some(edited)
+                        }
+                        Ok(None) => {
+                            self.replacement_decider.notify("... skipped ...");
+                            None
+                        }
+                        Err(err) => {
+                            self.replacement_decider
+                                .notify(&format!("... skipped: {} ...", err));
+                            None
+                        }
                     }
                 }
                 ReplacementDecision::Terminate => {
-                    println!("exiting!");
+                    self.replacement_decider.notify("exiting!");
                     *should_quit = true;
                     return Ok(0);
                 }
+                ReplacementDecision::Undo => {
+                    extra_context = 0;
+                    if decisions.pop().is_none() {
+                        self.replacement_decider
+                            .notify("... nothing to undo in this file ...");
+                    }
+                    continue;
+                }
+                ReplacementDecision::SkipFile => {
+                    decisions.resize(matches.len(), None);
+                    break;
+                }
+                ReplacementDecision::MoreContext => {
+                    extra_context += MORE_CONTEXT_LINES;
+                    continue;
+                }
             };
 
-            replacement_list.push(match_replacement);
+            extra_context = 0;
+            decisions.push(decision);
+        }
+
+        // Searches don't carry over into the next file, where they'd
+        // silently pass over everything.
+        if let Some(text) = self.replacement_decider.end_seek() {
+            self.replacement_decider.notify(&format!(
+                "... no more matches containing \"{}\" in this file ...",
+                text
+            ));
         }
 
+        let replacement_list: Vec<_> = matches
+            .into_iter()
+            .zip(decisions)
+            .filter_map(|(search_match, decision)| {
+                decision.map(|replacement| MatchReplacement {
+                    search_match,
+                    replacement: replacement.into(),
+                })
+            })
+            .collect();
+
         let num_replaced = if replacement_list.is_empty() {
             0
         } else {
             self.apply(path, &replacement_list)?
         };
 
+        if num_replaced > 0 {
+            events.handle(Event::Replaced { path, num_replaced })?;
+        }
+
         Ok(num_replaced)
     }
 
+    /// Compute the replacement line for a match without deciding on it.
+    pub fn propose(&self, search_match: &Match) -> Result<String> {
+        self.replace_match(search_match, &mut vec![])
+    }
+
+    /// Replace matches in `input` a line at a time, writing every
+    /// line to `output`. Returns how many lines were changed.
+    ///
+    /// Lines are replaced as bytes, so they needn't be valid UTF-8.
+    pub fn filter(&self, mut input: impl BufRead, mut output: impl Write) -> Result<usize> {
+        let mut buf = vec![];
+        let mut num_changed = 0;
+        loop {
+            buf.clear();
+            if input.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+
+            if !self.matcher().is_match(&buf)? {
+                output.write_all(&buf)?;
+                continue;
+            }
+
+            // Other occurrences may leave the line as it was.
+            let replacement = self.replace_line(&buf, None, None, &mut vec![])?;
+            if replacement != buf {
+                num_changed += 1;
+            }
+            output.write_all(&replacement)?;
+        }
+
+        output.flush()?;
+        Ok(num_changed)
+    }
+
+    /// Write the given replacements to `path`, skipping the decision
+    /// step. Replacements must be ordered by line number.
+    pub fn apply_accepted(&self, path: &Path, accepted: Vec<(Match, String)>) -> Result<usize> {
+        if accepted.is_empty() {
+            return Ok(0);
+        }
+
+        let replacement_list: Vec<_> = accepted
+            .into_iter()
+            .map(|(search_match, replacement)| MatchReplacement {
+                search_match,
+                replacement: replacement.into(),
+            })
+            .collect();
+
+        self.apply(path, &replacement_list)
+    }
+
+    /// Whether the file name of `path` matches FIND.
+    pub fn should_rename(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| self.matcher().is_match(name.as_bytes()).unwrap_or(false))
+            .unwrap_or(false)
+    }
+
+    /// Prompt for and apply renames of the given paths, returning the
+    /// number of paths renamed.
+    ///
+    /// Only the final component of each path is rewritten. Paths are
+    /// renamed deepest first, so directories are renamed after their
+    /// contents.
+    pub fn consume_renames(
+        &mut self,
+        mut paths: Vec<PathBuf>,
+        path_renamer: PathRenamer,
+        events: &mut dyn EventSink,
+        should_quit: &mut bool,
+    ) -> Result<usize> {
+        paths.sort_by(|a, b| {
+            let depth = |p: &PathBuf| p.components().count();
+            depth(b).cmp(&depth(a)).then_with(|| a.cmp(b))
+        });
+
+        self.replacement_decider.reset();
+
+        let mut renamed_to = HashSet::new();
+        let mut num_renamed = 0;
+        for path in paths {
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let new_name = self.replace_with_captures(file_name, None, &mut vec![])?;
+            let mut new_path = path.with_file_name(new_name);
+            if new_path == path {
+                continue;
+            }
+
+            if Self::rename_collides(&path, &new_path, &renamed_to, events)? {
+                continue;
+            }
+
+            events.handle(Event::Rename {
+                from: &path,
+                to: &new_path,
+            })?;
+
+            let proposal = Proposal::Rename {
+                from: &path,
+                to: &new_path,
+            };
+            match self.replacement_decider.decide(proposal) {
+                ReplacementDecision::Accept => {}
+                ReplacementDecision::Ignore => continue,
+                ReplacementDecision::Edit => {
+                    let input = self.replacement_decider.input();
+                    let line = match read_input("Rename to [^D to skip] ", input) {
+                        Ok(line) => line,
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => String::new(),
+                        Err(err) => return Err(err.into()),
+                    };
+                    if line.is_empty() {
+                        self.replacement_decider.notify("... skipped ...");
+                        continue;
+                    }
+
+                    new_path = path.with_file_name(line);
+                    if Self::rename_collides(&path, &new_path, &renamed_to, events)? {
+                        continue;
+                    }
+
+                    events.handle(Event::Rename {
+                        from: &path,
+                        to: &new_path,
+                    })?;
+                    self.replacement_decider.notify("--");
+                }
+                ReplacementDecision::Terminate => {
+                    self.replacement_decider.notify("exiting!");
+                    *should_quit = true;
+                    return Ok(num_renamed);
+                }
+                ReplacementDecision::SkipFile => break,
+                ReplacementDecision::Undo | ReplacementDecision::MoreContext => {
+                    self.replacement_decider
+                        .notify("... not available for renames, skipped ...");
+                    continue;
+                }
+            }
+
+            path_renamer.rename(&path, &new_path)?;
+            events.handle(Event::Renamed {
+                from: &path,
+                to: &new_path,
+            })?;
+            renamed_to.insert(new_path);
+            num_renamed += 1;
+        }
+
+        Ok(num_renamed)
+    }
+
+    fn rename_collides(
+        path: &Path,
+        new_path: &Path,
+        renamed_to: &HashSet<PathBuf>,
+        events: &mut dyn EventSink,
+    ) -> Result<bool> {
+        let collides = new_path.exists() || renamed_to.contains(new_path);
+        if collides {
+            events.handle(Event::Skipped {
+                path,
+                reason: &format!("not renamed, {} already exists", new_path.display()),
+            })?;
+        }
+
+        Ok(collides)
+    }
+
+    /// Write `replacements` to `path`. Each one stands for every line
+    /// of its match, and may itself be any number of lines, so lines
+    /// can be deleted or inserted as well as replaced.
     fn apply(&self, path: &Path, mut replacements: &[MatchReplacement]) -> Result<usize> {
         let src = File::open(path)?;
         let dst_file = NamedTempFile::new()?;
@@ -230,6 +748,12 @@ impl Replacer {
                 .unwrap_or(false);
 
             let new_line = if line_has_replacement {
+                // Structural matches replace every line they span.
+                for _ in 1..replacements[0].search_match.num_lines() {
+                    reader.read_line(&mut line)?;
+                    line_num += 1;
+                }
+
                 num_replaced += 1;
                 let repl = replacements[0].replacement.as_bytes();
                 replacements = &replacements[1..];
@@ -241,42 +765,308 @@ impl Replacer {
             writer.write_all(new_line)?;
         }
 
-        if !replacements.is_empty() {
-            panic!("reached EOF with remaining replacements");
-        }
+        // The file must have changed since it was searched.
+        ensure!(
+            replacements.is_empty(),
+            "reached end of file with {} replacements left, not written",
+            replacements.len()
+        );
 
         drop(writer);
         dst_file.persist(path)?;
         Ok(num_replaced)
     }
 
-    fn replace_with_captures(&self, input: &str) -> Result<String> {
-        let mut caps = self.regex_matcher.new_captures().unwrap();
+    /// The lines replacing those of a match.
+    fn replace_match(&self, m: &Match, swaps: &mut Vec<(String, String)>) -> Result<String> {
+        let replaced = self.replace_line(
+            m.line.1.as_bytes(),
+            m.replacement.as_deref(),
+            m.in_scope.as_deref(),
+            swaps,
+        )?;
+
+        Ok(String::from_utf8(replaced)
+            .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned()))
+    }
+
+    /// The lines replacing `line`, using `replacement` when the
+    /// searcher has already worked it out.
+    fn replace_line(
+        &self,
+        line: &[u8],
+        replacement: Option<&str>,
+        in_scope: Option<&[Range<usize>]>,
+        swaps: &mut Vec<(String, String)>,
+    ) -> Result<Vec<u8>> {
+        if self.line_edits.delete {
+            return Ok(vec![]);
+        }
+
+        let replaced = match replacement {
+            Some(replacement) => replacement.as_bytes().to_vec(),
+            None => self.replace_bytes(line, in_scope, swaps)?,
+        };
+
+        self.insert_lines(line, replaced)
+    }
+
+    /// Surround `replaced` with any lines to insert, interpolating
+    /// the captures of the first match in `input`.
+    fn insert_lines(&self, input: &[u8], mut replaced: Vec<u8>) -> Result<Vec<u8>> {
+        let LineEdits {
+            insert_before,
+            insert_after,
+            ..
+        } = &self.line_edits;
+        if insert_before.is_none() && insert_after.is_none() {
+            return Ok(replaced);
+        }
+
+        let mut caps = self.matcher().new_captures().unwrap();
+        self.matcher().captures(input, &mut caps)?;
+        let interpolate = |template: &str| {
+            let mut dst = vec![];
+            caps.interpolate(
+                |name| self.matcher().capture_index(name),
+                input,
+                template.as_bytes(),
+                &mut dst,
+            );
+            dst
+        };
+
+        if let Some(template) = insert_before {
+            let mut before = interpolate(template);
+            before.push(b'\n');
+            replaced.splice(0..0, before);
+        }
+        if let Some(template) = insert_after {
+            // The last line of a file may not end in a newline, and
+            // shouldn't gain one.
+            if replaced.ends_with(b"\n") {
+                replaced.extend(interpolate(template));
+                replaced.push(b'\n');
+            } else {
+                replaced.push(b'\n');
+                replaced.extend(interpolate(template));
+            }
+        }
+
+        Ok(replaced)
+    }
+
+    /// Replace every match in a file name, as with a line.
+    fn replace_with_captures(
+        &self,
+        input: &str,
+        in_scope: Option<&[Range<usize>]>,
+        swaps: &mut Vec<(String, String)>,
+    ) -> Result<String> {
+        let replaced = self.replace_bytes(input.as_bytes(), in_scope, swaps)?;
+        Ok(String::from_utf8_lossy(&replaced).into_owned())
+    }
+
+    /// Replace every match in `input`, or only the selected
+    /// occurrence. Matches starting outside of `in_scope` are left
+    /// alone and not counted as occurrences.
+    ///
+    /// When swapping, each exchange made is recorded in `swaps` as
+    /// `(matched text, replacement)` so the preview can show which
+    /// direction it went.
+    fn replace_bytes(
+        &self,
+        input: &[u8],
+        in_scope: Option<&[Range<usize>]>,
+        swaps: &mut Vec<(String, String)>,
+    ) -> Result<Vec<u8>> {
+        let mut caps = self.matcher().new_captures().unwrap();
         let mut dst = vec![];
+        let mut num_seen = 0;
+
+        self.matcher()
+            .replace_with_captures(input, &mut caps, &mut dst, |caps, dst| {
+                let start = caps.get(0).map_or(0, |m| m.start());
+                let is_in_scope =
+                    in_scope.is_none_or(|ranges| ranges.iter().any(|r| r.contains(&start)));
+                if is_in_scope {
+                    num_seen += 1;
+                }
+
+                if !is_in_scope || matches!(self.occurrence, Some(n) if n != num_seen) {
+                    // Leave other occurrences as they were.
+                    let matched = caps.get(0).map_or(&[][..], |m| &input[m]);
+                    dst.extend_from_slice(matched);
+                    return true;
+                }
+
+                match &self.replacement_template {
+                    ReplacementTemplate::Captures(template) => caps.interpolate(
+                        |name| self.matcher().capture_index(name),
+                        input,
+                        template.as_bytes(),
+                        dst,
+                    ),
+
+                    ReplacementTemplate::Swap(swap) => {
+                        let is_left = self
+                            .matcher()
+                            .capture_index(Swap::LEFT_GROUP)
+                            .and_then(|idx| caps.get(idx))
+                            .is_some();
+
+                        let replacement = if is_left { &swap.right } else { &swap.left };
+                        let matched = caps.get(0).map_or(&[][..], |m| &input[m]);
+
+                        dst.extend_from_slice(replacement.as_bytes());
+                        swaps.push((
+                            String::from_utf8_lossy(matched).into_owned(),
+                            replacement.to_owned(),
+                        ));
+                    }
 
-        self.regex_matcher.replace_with_captures(
-            input.as_bytes(),
-            &mut caps,
-            &mut dst,
-            |caps, dst| {
-                caps.interpolate(
-                    |name| self.regex_matcher.capture_index(name),
-                    input.as_bytes(),
-                    self.replacement_template.as_bytes(),
-                    dst,
-                );
+                    ReplacementTemplate::Unchanged => {
+                        let matched = caps.get(0).map_or(&[][..], |m| &input[m]);
+                        dst.extend_from_slice(matched);
+                    }
+                }
                 true
-            },
-        )?;
+            })?;
+
+        Ok(dst)
+    }
+}
 
-        Ok(String::from_utf8_lossy(&dst).to_string())
+/// Lines added before and after a match each time more context is
+/// requested.
+const MORE_CONTEXT_LINES: usize = 5;
+
+/// Copy of `search_match` with `extra` more lines of context on each
+/// side, read from the file.
+fn with_more_context(path: &Path, search_match: &Match, extra: usize) -> Result<Match> {
+    let contents = std::fs::read_to_string(path)?;
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+
+    let idx = search_match.line.0 as usize - 1;
+    let end = idx + search_match.num_lines();
+    let before = search_match.context_pre.len() + extra;
+    let after = search_match.context_post.len() + extra;
+
+    let line_at = |i: usize| Line(i as u64 + 1, lines[i].to_owned());
+    Ok(Match {
+        line: search_match.line.clone(),
+        context_pre: (idx.saturating_sub(before)..idx).map(line_at).collect(),
+        context_post: (end..lines.len().min(end + after)).map(line_at).collect(),
+        in_scope: search_match.in_scope.clone(),
+        replacement: search_match.replacement.clone(),
+    })
+}
+
+/// Read a line from `reader` without its line terminator. Running
+/// out of input is an error, as no answer will ever come.
+fn read_answer(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
     }
+
+    Ok(line.trim_end_matches(['\n', '\r']).to_owned())
 }
 
 // TODO: global mutex.
-fn read_input(prompt: &str) -> Result<String, std::io::Error> {
+fn read_input(prompt: &str, input: PromptInput) -> Result<String, std::io::Error> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+
+    input.read_line()
+}
+
+/// Puts the terminal into raw mode until dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> std::io::Result<RawMode> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Read a single key press without waiting for Enter.
+///
+/// Falls back to reading a whole line when the input isn't a
+/// terminal. `/` is followed by a line of text to search for. Ctrl-C
+/// is an `Interrupted` error, as raw mode has already been left.
+fn read_key(prompt: &str, input: PromptInput) -> Result<String, std::io::Error> {
+    // Raw mode applies to the controlling terminal even when standard
+    // input is redirected.
+    if !input.is_terminal() {
+        return read_input(prompt, input);
+    }
+
     print!("{}", prompt);
     std::io::stdout().flush()?;
 
-    Ok(read!("{}\n"))
+    let key = {
+        let _raw_mode = RawMode::enable()?;
+        loop {
+            let key = match event::read()? {
+                event::Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            match key.code {
+                // Signals aren't generated in raw mode, so Ctrl-C
+                // arrives as a key press.
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break None,
+                KeyCode::Char(c) => break Some(c.to_string()),
+                KeyCode::Enter => break Some(String::new()),
+                _ => continue,
+            }
+        }
+    };
+
+    let key = match key {
+        Some(key) => key,
+        None => {
+            println!("^C");
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+    };
+
+    if key == "/" {
+        print!("/");
+        std::io::stdout().flush()?;
+
+        let text = input.read_line()?;
+        return Ok(format!("/{}", text));
+    }
+
+    println!("{}", key);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_read_answer() {
+        // Answers typed ahead are kept for the next read.
+        let mut reader = Cursor::new("y\n/foo\r\nn");
+        assert_eq!(read_answer(&mut reader).unwrap(), "y");
+        assert_eq!(read_answer(&mut reader).unwrap(), "/foo");
+        assert_eq!(read_answer(&mut reader).unwrap(), "n");
+        assert_eq!(
+            read_answer(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
 }
@@ -0,0 +1,637 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use grep::matcher::Matcher;
+
+use crate::matcher::PatternMatcher;
+use crate::search::Match;
+
+/// Which parts of source files replacements are limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Everything outside comments and string literals.
+    Code,
+    Comments,
+    Strings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Comment,
+    String,
+}
+
+struct StringSyntax {
+    /// Comes before the opening delimiter only, as with C#'s `@"..."`.
+    prefix: &'static str,
+    delimiter: &'static str,
+    escapes: bool,
+    multiline: bool,
+}
+
+const fn string(delimiter: &'static str, escapes: bool, multiline: bool) -> StringSyntax {
+    prefixed_string("", delimiter, escapes, multiline)
+}
+
+const fn prefixed_string(
+    prefix: &'static str,
+    delimiter: &'static str,
+    escapes: bool,
+    multiline: bool,
+) -> StringSyntax {
+    StringSyntax {
+        prefix,
+        delimiter,
+        escapes,
+        multiline,
+    }
+}
+
+/// Just enough of a language's syntax to tell comments and strings
+/// from code.
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comments: &'static [(&'static str, &'static str)],
+    /// Longer delimiters come first, so `"""` is tried before `"`.
+    strings: &'static [StringSyntax],
+    /// Line comments only start at the beginning of a word, as in
+    /// shell where `$#` isn't a comment.
+    comments_after_space: bool,
+    /// Block comments may contain other block comments.
+    nested_comments: bool,
+    /// Rust's raw strings, `r"..."` or `r#"..."#` with any number of
+    /// `#`s, optionally prefixed by `b` or `c`.
+    raw_strings: bool,
+    /// Single quotes are a character literal if they close after one
+    /// character or escape, and are otherwise left alone, as with
+    /// Rust's lifetimes or Scala's symbols.
+    char_literals: bool,
+}
+
+const RUST: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[string("\"", true, true)],
+    comments_after_space: false,
+    nested_comments: true,
+    raw_strings: true,
+    char_literals: true,
+};
+
+const C_FAMILY: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[string("\"", true, false), string("'", true, false)],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const JAVA: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[
+        string("\"\"\"", true, true),
+        string("\"", true, false),
+        string("'", true, false),
+    ],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const CSHARP: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[
+        string("\"\"\"", false, true),
+        prefixed_string("@", "\"", false, true),
+        string("\"", true, false),
+        string("'", true, false),
+    ],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+/// Kotlin and Scala.
+const KOTLIN: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[string("\"\"\"", false, true), string("\"", true, false)],
+    comments_after_space: false,
+    nested_comments: true,
+    raw_strings: false,
+    char_literals: true,
+};
+
+const SWIFT: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[string("\"\"\"", true, true), string("\"", true, false)],
+    comments_after_space: false,
+    nested_comments: true,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const JAVASCRIPT: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[
+        string("`", true, true),
+        string("\"", true, false),
+        string("'", true, false),
+    ],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const GO: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[
+        string("`", false, true),
+        string("\"", true, false),
+        string("'", true, false),
+    ],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const PYTHON: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comments: &[],
+    strings: &[
+        string("\"\"\"", true, true),
+        string("'''", true, true),
+        string("\"", true, false),
+        string("'", true, false),
+    ],
+    comments_after_space: false,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+const SHELL: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comments: &[],
+    strings: &[string("\"", true, true), string("'", false, true)],
+    comments_after_space: true,
+    nested_comments: false,
+    raw_strings: false,
+    char_literals: false,
+};
+
+fn syntax_for(path: &Path) -> Option<&'static Syntax> {
+    let syntax = match path.extension()?.to_str()? {
+        "rs" => &RUST,
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => &C_FAMILY,
+        "java" => &JAVA,
+        "cs" => &CSHARP,
+        "kt" | "kts" | "scala" => &KOTLIN,
+        "swift" => &SWIFT,
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "mts" | "cts" => &JAVASCRIPT,
+        "go" => &GO,
+        "py" | "pyi" => &PYTHON,
+        "sh" | "bash" | "zsh" => &SHELL,
+        _ => return None,
+    };
+
+    Some(syntax)
+}
+
+/// Byte ranges of the comments and string literals in `contents`,
+/// including their delimiters.
+fn tokenize(contents: &[u8], syntax: &Syntax) -> Vec<(Range<usize>, TokenKind)> {
+    let find = |from: usize, needle: &[u8]| {
+        contents[from..]
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|i| from + i)
+    };
+
+    let mut tokens = vec![];
+    let mut i = 0;
+    'outer: while i < contents.len() {
+        let rest = &contents[i..];
+        let at_word_start = i == 0 || contents[i - 1].is_ascii_whitespace();
+
+        for prefix in syntax.line_comments {
+            if rest.starts_with(prefix.as_bytes())
+                && (at_word_start || !syntax.comments_after_space)
+            {
+                let end = find(i, b"\n").unwrap_or(contents.len());
+                tokens.push((i..end, TokenKind::Comment));
+                i = end;
+                continue 'outer;
+            }
+        }
+
+        for (open, close) in syntax.block_comments {
+            if rest.starts_with(open.as_bytes()) {
+                let end = if syntax.nested_comments {
+                    nested_comment_end(contents, i + open.len(), open, close)
+                } else {
+                    find(i + open.len(), close.as_bytes())
+                        .map_or(contents.len(), |j| j + close.len())
+                };
+                tokens.push((i..end, TokenKind::Comment));
+                i = end;
+                continue 'outer;
+            }
+        }
+
+        if syntax.raw_strings && !is_identifier_byte(i.checked_sub(1).map(|j| contents[j])) {
+            if let Some(end) = raw_string_end(contents, i) {
+                tokens.push((i..end, TokenKind::String));
+                i = end;
+                continue;
+            }
+        }
+
+        if syntax.char_literals && rest.starts_with(b"'") {
+            match char_literal_end(contents, i) {
+                Some(end) => {
+                    tokens.push((i..end, TokenKind::String));
+                    i = end;
+                }
+                None => i += 1,
+            }
+            continue;
+        }
+
+        for string in syntax.strings {
+            let opening = rest
+                .strip_prefix(string.prefix.as_bytes())
+                .is_some_and(|rest| rest.starts_with(string.delimiter.as_bytes()));
+            if opening {
+                let from = i + string.prefix.len() + string.delimiter.len();
+                let end = string_end(contents, from, string);
+                tokens.push((i..end, TokenKind::String));
+                i = end;
+                continue 'outer;
+            }
+        }
+
+        i += 1;
+    }
+
+    tokens
+}
+
+/// Where a string whose contents begin at `from` ends, just past its
+/// closing delimiter. Unterminated strings end at the end of their
+/// line if they can't span lines, or otherwise at the end of input.
+fn string_end(bytes: &[u8], from: usize, string: &StringSyntax) -> usize {
+    let delimiter = string.delimiter.as_bytes();
+
+    let mut i = from;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(delimiter) {
+            return i + delimiter.len();
+        }
+
+        match bytes[i] {
+            b'\\' if string.escapes => i += 2,
+            b'\n' if !string.multiline => return i,
+            _ => i += 1,
+        }
+    }
+
+    bytes.len()
+}
+
+/// Where a block comment whose contents begin at `from` ends, just
+/// past the `close` matching its opening.
+fn nested_comment_end(bytes: &[u8], from: usize, open: &str, close: &str) -> usize {
+    let mut depth = 1;
+    let mut i = from;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(close.as_bytes()) {
+            i += close.len();
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        } else if bytes[i..].starts_with(open.as_bytes()) {
+            i += open.len();
+            depth += 1;
+        } else {
+            i += 1;
+        }
+    }
+
+    bytes.len()
+}
+
+fn is_identifier_byte(byte: Option<u8>) -> bool {
+    byte.is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Where a raw string starting at `start` ends, or `None` if there
+/// isn't one there.
+fn raw_string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start;
+    if matches!(bytes.get(i), Some(b'b' | b'c')) {
+        i += 1;
+    }
+    if bytes.get(i) != Some(&b'r') {
+        return None;
+    }
+    i += 1;
+
+    let num_hashes = bytes[i..].iter().take_while(|&&b| b == b'#').count();
+    i += num_hashes;
+    if bytes.get(i) != Some(&b'"') {
+        return None;
+    }
+    i += 1;
+
+    let mut close = vec![b'"'];
+    close.resize(1 + num_hashes, b'#');
+    let end = bytes[i..]
+        .windows(close.len())
+        .position(|window| window == close)
+        .map_or(bytes.len(), |j| i + j + close.len());
+
+    Some(end)
+}
+
+/// Where a character literal starting with the quote at `start` ends,
+/// or `None` if the quote doesn't start one.
+fn char_literal_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let i = start + 1;
+    let close = match *bytes.get(i)? {
+        // Any escape, up to the closing quote on the same line.
+        b'\\' => {
+            let from = (i + 2).min(bytes.len());
+            from + bytes[from..]
+                .iter()
+                .position(|&b| b == b'\'' || b == b'\n')?
+        }
+        b'\n' | b'\'' => return None,
+        // Otherwise one character, however many bytes it takes.
+        b if b < 0x80 => i + 1,
+        b if b >= 0xf0 => i + 4,
+        b if b >= 0xe0 => i + 3,
+        _ => i + 2,
+    };
+
+    (bytes.get(close) == Some(&b'\'')).then_some(close + 1)
+}
+
+/// Drops matches outside of a [`Scope`].
+pub struct ScopeFilter {
+    scope: Scope,
+    matcher: Arc<PatternMatcher>,
+}
+
+impl ScopeFilter {
+    pub fn new(scope: Scope, matcher: Arc<PatternMatcher>) -> ScopeFilter {
+        ScopeFilter { scope, matcher }
+    }
+
+    /// Drop matches of `path` with no occurrence inside the scope,
+    /// and mark which parts of the remaining lines are in scope.
+    ///
+    /// Files in languages we can't tokenize are skipped entirely,
+    /// since we can't tell what's in scope.
+    pub fn retain_in_scope(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        matches: &mut Vec<Match>,
+    ) -> Result<()> {
+        let syntax = match syntax_for(path) {
+            Some(syntax) if !matches.is_empty() => syntax,
+            _ => {
+                matches.clear();
+                return Ok(());
+            }
+        };
+
+        let ranges = self.ranges_in_scope(contents, syntax);
+
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                contents
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &b)| b == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+
+        matches.retain_mut(|m| {
+            let line_start = line_starts[m.line.0 as usize - 1];
+            let line_end = line_start + m.line.1.len();
+
+            let in_scope: Vec<Range<usize>> = ranges
+                .iter()
+                .filter(|range| range.start < line_end && line_start < range.end)
+                .map(|range| {
+                    range.start.max(line_start) - line_start..range.end.min(line_end) - line_start
+                })
+                .collect();
+
+            let mut has_occurrence = false;
+            let _ = self.matcher.find_iter(m.line.1.as_bytes(), |found| {
+                has_occurrence = in_scope.iter().any(|range| range.contains(&found.start()));
+                !has_occurrence
+            });
+
+            m.in_scope = Some(in_scope);
+            has_occurrence
+        });
+
+        Ok(())
+    }
+
+    fn ranges_in_scope(&self, contents: &[u8], syntax: &Syntax) -> Vec<Range<usize>> {
+        let tokens = tokenize(contents, syntax);
+
+        let kind = match self.scope {
+            Scope::Comments => TokenKind::Comment,
+            Scope::Strings => TokenKind::String,
+            Scope::Code => {
+                // Code is everything between tokens.
+                let mut ranges = vec![];
+                let mut start = 0;
+                for (range, _) in tokens {
+                    ranges.push(start..range.start);
+                    start = range.end;
+                }
+                ranges.push(start..contents.len());
+                ranges.retain(|range| !range.is_empty());
+                return ranges;
+            }
+        };
+
+        tokens
+            .into_iter()
+            .filter(|(_, token_kind)| *token_kind == kind)
+            .map(|(range, _)| range)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grep::regex::RegexMatcher;
+
+    use super::*;
+
+    fn tokens<'a>(contents: &'a str, syntax: &Syntax) -> Vec<(&'a str, TokenKind)> {
+        tokenize(contents.as_bytes(), syntax)
+            .into_iter()
+            .map(|(range, kind)| (&contents[range], kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_rust() {
+        let contents = r#"let s = "a \" // b"; // c
+/* d
+e */ f('x');
+"#;
+        assert_eq!(
+            tokens(contents, &RUST),
+            vec![
+                (r#""a \" // b""#, TokenKind::String),
+                ("// c", TokenKind::Comment),
+                ("/* d\ne */", TokenKind::Comment),
+                ("'x'", TokenKind::String),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_rust_char_literals() {
+        let contents = r#"let q = '"'; let e = '\''; fn f<'a>(x: &'a str) -> char { 'é' }"#;
+        assert_eq!(
+            tokens(contents, &RUST),
+            vec![
+                ("'\"'", TokenKind::String),
+                (r"'\''", TokenKind::String),
+                ("'é'", TokenKind::String),
+            ]
+        );
+
+        // A lifetime at the end of input or before a newline.
+        assert_eq!(tokens("'a\n'static", &RUST), vec![]);
+    }
+
+    #[test]
+    fn test_tokenize_rust_raw_strings() {
+        let contents = r###"let s = r#"a " // b"#; let t = br"\"; for"x" // c"###;
+        assert_eq!(
+            tokens(contents, &RUST),
+            vec![
+                (r###"r#"a " // b"#"###, TokenKind::String),
+                (r#"br"\""#, TokenKind::String),
+                (r#""x""#, TokenKind::String),
+                ("// c", TokenKind::Comment),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_nested_comments() {
+        let contents = "/* a /* b */ c */ d /* e";
+        assert_eq!(
+            tokens(contents, &RUST),
+            vec![
+                ("/* a /* b */ c */", TokenKind::Comment),
+                ("/* e", TokenKind::Comment),
+            ]
+        );
+        assert_eq!(
+            tokens("/* a /* b */ c */", &C_FAMILY),
+            vec![("/* a /* b */", TokenKind::Comment)]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_triple_quoted_strings() {
+        let contents = "val s = \"\"\"a \" // b\"\"\" // c\n";
+        for syntax in [&JAVA, &CSHARP, &KOTLIN, &SWIFT] {
+            assert_eq!(
+                tokens(contents, syntax),
+                vec![
+                    ("\"\"\"a \" // b\"\"\"", TokenKind::String),
+                    ("// c", TokenKind::Comment),
+                ]
+            );
+        }
+
+        assert_eq!(
+            tokens("var s = @\"C:\\\"; // c", &CSHARP),
+            vec![
+                ("@\"C:\\\"", TokenKind::String),
+                ("// c", TokenKind::Comment),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_python() {
+        let contents = "x = '''a\n# b''' # c\ny = \"d\n";
+        assert_eq!(
+            tokens(contents, &PYTHON),
+            vec![
+                ("'''a\n# b'''", TokenKind::String),
+                ("# c", TokenKind::Comment),
+                ("\"d", TokenKind::String),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_shell() {
+        let contents = "echo $# 'a # b' # c\n";
+        assert_eq!(
+            tokens(contents, &SHELL),
+            vec![("'a # b'", TokenKind::String), ("# c", TokenKind::Comment)]
+        );
+    }
+
+    #[test]
+    fn test_code_after_char_literal() {
+        let matcher = Arc::new(PatternMatcher::Regex(RegexMatcher::new("x").unwrap()));
+        let filter = ScopeFilter::new(Scope::Code, matcher);
+        let contents = b"let q = '\"'; foo();";
+
+        assert_eq!(filter.ranges_in_scope(contents, &RUST), vec![0..8, 11..19]);
+    }
+
+    #[test]
+    fn test_ranges_in_scope() {
+        let matcher = Arc::new(PatternMatcher::Regex(RegexMatcher::new("x").unwrap()));
+        let filter = |scope| ScopeFilter::new(scope, matcher.clone());
+        let contents = b"a \"b\" // c\n";
+
+        assert_eq!(
+            filter(Scope::Code).ranges_in_scope(contents, &RUST),
+            vec![0..2, 5..6, 10..11]
+        );
+        assert_eq!(
+            filter(Scope::Strings).ranges_in_scope(contents, &RUST),
+            vec![2..5]
+        );
+        assert_eq!(
+            filter(Scope::Comments).ranges_in_scope(contents, &RUST),
+            vec![6..10]
+        );
+    }
+}
@@ -1,9 +1,17 @@
+use std::fs::File;
+use std::io::Read;
+use std::ops::{Range, RangeInclusive};
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
-use grep::regex::RegexMatcher;
+use grep::matcher::Matcher;
 use grep::searcher::{SinkContext, SinkContextKind, SinkMatch};
+use regex::bytes::Regex;
+
+use crate::matcher::PatternMatcher;
+#[cfg(feature = "tree-sitter")]
+use crate::tree_query::TreeQuery;
 
 #[derive(Debug, Clone)]
 pub struct Line(pub u64, pub String);
@@ -13,6 +21,22 @@ pub struct Match {
     pub line: Line,
     pub context_pre: Vec<Line>,
     pub context_post: Vec<Line>,
+
+    /// Byte ranges of `line` in the scope being replaced, or `None`
+    /// if all of it is.
+    pub in_scope: Option<Vec<Range<usize>>>,
+
+    /// The replacement for `line`, if the searcher works it out
+    /// itself rather than leaving it to the replacement template.
+    pub replacement: Option<String>,
+}
+
+impl Match {
+    /// How many lines the match spans. Only structural and tree-sitter
+    /// query matches span more than one.
+    pub fn num_lines(&self) -> usize {
+        self.line.1.split_inclusive('\n').count().max(1)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -31,11 +55,15 @@ struct MatchCollector {
     cur_match_line: Option<Line>,
 
     matches: Vec<Match>,
+    max_matches: usize,
+    regions: Option<RegionTracker>,
 }
 
 impl MatchCollector {
-    fn new() -> MatchCollector {
+    fn new(max_matches: usize, regions: Option<RegionTracker>) -> MatchCollector {
         MatchCollector {
+            max_matches,
+            regions,
             state: MatchState::Before,
             cur_match_line: None,
             cur_context_pre: Vec::new(),
@@ -58,6 +86,8 @@ impl MatchCollector {
                 line,
                 context_pre,
                 context_post,
+                in_scope: None,
+                replacement: None,
             };
 
             self.matches.push(search_match);
@@ -65,6 +95,10 @@ impl MatchCollector {
         }
     }
 
+    fn num_matches(&self) -> usize {
+        self.matches.len() + usize::from(self.cur_match_line.is_some())
+    }
+
     #[inline]
     fn transition(&mut self, next: MatchState) {
         match (self.state, next) {
@@ -74,6 +108,9 @@ impl MatchCollector {
             | (MatchState::After, MatchState::Before)     // Have before context, have after context
             | (MatchState::After, MatchState::Match) => { // Have after context, no before context
                 self.maybe_emit();
+                // Emitting resets the state, which would otherwise
+                // drop the next of several consecutive matching lines.
+                self.state = next;
             }
 
             (_prev, next) => {
@@ -100,10 +137,26 @@ impl grep::searcher::Sink for MatchCollector {
         _searcher: &grep::searcher::Searcher,
         mat: &SinkMatch<'_>,
     ) -> Result<bool, std::io::Error> {
+        // Stop at the first match past the limit, rather than at the
+        // last one within it, so that its after context is kept.
+        if self.num_matches() >= self.max_matches {
+            return Ok(false);
+        }
+
+        let line_number = mat.line_number().unwrap();
+        if let Some(regions) = &mut self.regions {
+            if regions.is_past_end(line_number) {
+                return Ok(false);
+            }
+            if !regions.contains(line_number) {
+                return Ok(true);
+            }
+        }
+
         self.transition(MatchState::Match);
 
         let line = Line(
-            mat.line_number().unwrap(),
+            line_number,
             String::from_utf8_lossy(mat.bytes()).to_string(),
         );
 
@@ -138,43 +191,346 @@ impl grep::searcher::Sink for MatchCollector {
     }
 }
 
+/// Lines which matches are limited to: a range of line numbers,
+/// regions between marker lines, or both.
+#[derive(Debug, Clone, Default)]
+pub struct Regions {
+    lines: Option<RangeInclusive<u64>>,
+    markers: Option<(Regex, Regex)>,
+}
+
+impl Regions {
+    pub fn lines(mut self, lines: RangeInclusive<u64>) -> Regions {
+        self.lines = Some(lines);
+        self
+    }
+
+    /// Regions start after a line matching `start`, and end before
+    /// the next line matching `end`.
+    pub fn between(mut self, start: Regex, end: Regex) -> Regions {
+        self.markers = Some((start, end));
+        self
+    }
+
+    /// Whether finding regions needs the file's contents.
+    fn needs_contents(&self) -> bool {
+        self.markers.is_some()
+    }
+
+    /// The tracker for a file with `contents`, or `None` if every line
+    /// is allowed. `contents` is only looked at with markers.
+    fn tracker(&self, contents: &[u8]) -> Option<RegionTracker> {
+        let mut ranges = match &self.markers {
+            Some((start, end)) => marked_regions(contents, start, end),
+            None if self.lines.is_some() => vec![1..=u64::MAX],
+            None => return None,
+        };
+
+        if let Some(lines) = &self.lines {
+            ranges = ranges
+                .into_iter()
+                .map(|range| *range.start().max(lines.start())..=*range.end().min(lines.end()))
+                .filter(|range| !range.is_empty())
+                .collect();
+        }
+
+        Some(RegionTracker { ranges, next: 0 })
+    }
+}
+
+/// Line ranges strictly between `start` and `end` markers.
+///
+/// Markers nest, so a region only ends once every `start` inside it
+/// has been closed. A region that is never closed runs to the end of
+/// the file, like the last section of a Markdown document.
+fn marked_regions(contents: &[u8], start: &Regex, end: &Regex) -> Vec<RangeInclusive<u64>> {
+    let mut regions = vec![];
+    let mut depth = 0;
+    let mut region_start = 0;
+
+    for (line_number, line) in (1..).zip(contents.split(|&b| b == b'\n')) {
+        if depth > 0 && end.is_match(line) {
+            depth -= 1;
+            if depth == 0 && region_start < line_number {
+                regions.push(region_start..=line_number - 1);
+            }
+        } else if start.is_match(line) {
+            if depth == 0 {
+                region_start = line_number + 1;
+            }
+            depth += 1;
+        }
+    }
+
+    if depth > 0 {
+        regions.push(region_start..=u64::MAX);
+    }
+
+    regions
+}
+
+/// Follows matched lines through a file's regions. Lines must be
+/// checked in increasing order.
+#[derive(Debug)]
+struct RegionTracker {
+    ranges: Vec<RangeInclusive<u64>>,
+    next: usize,
+}
+
+impl RegionTracker {
+    fn contains(&mut self, line_number: u64) -> bool {
+        while self
+            .ranges
+            .get(self.next)
+            .is_some_and(|range| *range.end() < line_number)
+        {
+            self.next += 1;
+        }
+
+        self.ranges
+            .get(self.next)
+            .is_some_and(|range| range.contains(&line_number))
+    }
+
+    /// Whether no region remains at or after `line_number`.
+    fn is_past_end(&self, line_number: u64) -> bool {
+        self.ranges
+            .last()
+            .is_none_or(|range| *range.end() < line_number)
+    }
+}
+
+/// Searches files for FIND, either line by line with a pattern, or
+/// through each file's syntax tree with a tree-sitter query.
+///
+/// One is built per thread, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum Searcher {
+    Pattern(RegexSearcher),
+    #[cfg(feature = "tree-sitter")]
+    TreeQuery(Arc<TreeQuery>),
+}
+
+impl Searcher {
+    /// Search `path`, stopping once `max_matches` have been found.
+    pub fn search_path(&mut self, path: &Path, max_matches: usize) -> Result<Vec<Match>> {
+        match self {
+            Searcher::Pattern(searcher) => searcher.search_path(path, max_matches),
+            #[cfg(feature = "tree-sitter")]
+            Searcher::TreeQuery(query) => query.search_path(path, max_matches),
+        }
+    }
+
+    /// The contents of the file last searched, if they were kept.
+    pub fn contents(&self) -> Option<&[u8]> {
+        match self {
+            Searcher::Pattern(searcher) => searcher.contents(),
+            #[cfg(feature = "tree-sitter")]
+            Searcher::TreeQuery(_) => None,
+        }
+    }
+}
+
 pub struct RegexSearcher {
     searcher: grep::searcher::Searcher,
-    matcher: Arc<RegexMatcher>,
+    matcher: Arc<PatternMatcher>,
+    min_occurrences: usize,
+    regions: Arc<Regions>,
+
+    /// Whether to read each file whole before searching it, so that
+    /// it's only read once when something else needs its contents.
+    reads_contents: bool,
+    contents: Vec<u8>,
 }
 
 impl RegexSearcher {
-    pub fn search_path(&mut self, path: &'_ Path) -> Result<Vec<Match>> {
-        let mut collector = MatchCollector::new();
+    /// Search `path`, stopping once `max_matches` have been found.
+    pub fn search_path(&mut self, path: &'_ Path, max_matches: usize) -> Result<Vec<Match>> {
+        self.contents.clear();
+        if self.reads_contents {
+            File::open(path)?.read_to_end(&mut self.contents)?;
+        }
+
+        let regions = self.regions.tracker(&self.contents);
+        if matches!(&regions, Some(tracker) if tracker.ranges.is_empty()) {
+            return Ok(vec![]);
+        }
 
-        self.searcher
-            .search_path(self.matcher.as_ref(), path, &mut collector)?;
+        // Lines with too few occurrences are only dropped once the
+        // search is done, so the limit can't be applied until then.
+        let filters_lines = self.min_occurrences > 1;
+        let mut collector = MatchCollector::new(
+            if filters_lines {
+                usize::MAX
+            } else {
+                max_matches
+            },
+            regions,
+        );
+
+        if self.reads_contents {
+            self.searcher
+                .search_slice(self.matcher.as_ref(), &self.contents, &mut collector)?;
+        } else {
+            self.searcher
+                .search_path(self.matcher.as_ref(), path, &mut collector)?;
+        }
+
+        let mut matches = collector.collect();
+        if filters_lines {
+            matches.retain(|m| self.has_min_occurrences(&m.line.1));
+            matches.truncate(max_matches);
+        }
 
-        let matches = collector.collect();
         Ok(matches)
     }
+
+    /// The contents of the file last searched, if files are read whole
+    /// for the searcher's regions or because `keep_contents` was set.
+    pub fn contents(&self) -> Option<&[u8]> {
+        self.reads_contents.then_some(&self.contents[..])
+    }
+
+    fn has_min_occurrences(&self, line: &str) -> bool {
+        let mut num_occurrences = 0;
+        let _ = self.matcher.find_iter(line.as_bytes(), |_| {
+            num_occurrences += 1;
+            num_occurrences < self.min_occurrences
+        });
+
+        num_occurrences >= self.min_occurrences
+    }
+}
+
+pub enum SearcherFactory {
+    Pattern(RegexSearcherFactory),
+    #[cfg(feature = "tree-sitter")]
+    TreeQuery(Arc<TreeQuery>),
+}
+
+impl SearcherFactory {
+    pub fn build(&self) -> Searcher {
+        match self {
+            SearcherFactory::Pattern(factory) => Searcher::Pattern(factory.build()),
+            #[cfg(feature = "tree-sitter")]
+            SearcherFactory::TreeQuery(query) => Searcher::TreeQuery(query.clone()),
+        }
+    }
 }
 
 pub struct RegexSearcherFactory {
     searcher_builder: grep::searcher::SearcherBuilder,
-    pattern_matcher: Arc<RegexMatcher>,
+    pattern_matcher: Arc<PatternMatcher>,
+    min_occurrences: usize,
+    regions: Arc<Regions>,
+    keep_contents: bool,
 }
 
 impl RegexSearcherFactory {
+    /// Searchers only report lines within `regions` with at least
+    /// `min_occurrences` matches of the pattern.
     pub fn new(
         searcher_builder: grep::searcher::SearcherBuilder,
-        pattern_matcher: Arc<RegexMatcher>,
+        pattern_matcher: Arc<PatternMatcher>,
+        min_occurrences: usize,
+        regions: Regions,
     ) -> RegexSearcherFactory {
         RegexSearcherFactory {
             searcher_builder,
             pattern_matcher,
+            min_occurrences,
+            regions: Arc::new(regions),
+            keep_contents: false,
         }
     }
 
+    /// Keep the contents of each file searched, for `contents`.
+    pub fn keep_contents(mut self, keep_contents: bool) -> RegexSearcherFactory {
+        self.keep_contents = keep_contents;
+        self
+    }
+
     pub fn build(&self) -> RegexSearcher {
         RegexSearcher {
             searcher: self.searcher_builder.build(),
             matcher: self.pattern_matcher.clone(),
+            min_occurrences: self.min_occurrences,
+            regions: self.regions.clone(),
+            reads_contents: self.keep_contents || self.regions.needs_contents(),
+            contents: vec![],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions(contents: &str) -> Vec<RangeInclusive<u64>> {
+        let start = Regex::new("BEGIN").unwrap();
+        let end = Regex::new("END").unwrap();
+        marked_regions(contents.as_bytes(), &start, &end)
+    }
+
+    fn matching_lines(contents: &str, context: usize) -> Vec<u64> {
+        let matcher = grep::regex::RegexMatcher::new("foo").unwrap();
+        let mut searcher = grep::searcher::SearcherBuilder::new()
+            .before_context(context)
+            .after_context(context)
+            .build();
+
+        let mut collector = MatchCollector::new(usize::MAX, None);
+        searcher
+            .search_slice(&matcher, contents.as_bytes(), &mut collector)
+            .unwrap();
+        collector.collect().iter().map(|m| m.line.0).collect()
+    }
+
+    #[test]
+    fn test_consecutive_matches() {
+        assert_eq!(matching_lines("foo\nfoo\nfoo\nbar\n", 0), vec![1, 2, 3]);
+        assert_eq!(
+            matching_lines("bar\nfoo\nfoo\nfoo\nbar\n", 1),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_marked_regions() {
+        assert_eq!(regions("a\nBEGIN\nb\nc\nEND\nd\n"), vec![3..=4]);
+        assert_eq!(regions("BEGIN\nEND\nBEGIN\na\nEND\n"), vec![4..=4]);
+        assert_eq!(regions("a\nEND\nb\n"), vec![]);
+    }
+
+    #[test]
+    fn test_nested_regions() {
+        assert_eq!(regions("BEGIN\na\nBEGIN\nb\nEND\nc\nEND\nd\n"), vec![2..=6]);
+    }
+
+    #[test]
+    fn test_unterminated_region() {
+        assert_eq!(regions("a\nBEGIN\nb\n"), vec![3..=u64::MAX]);
+        assert_eq!(regions("BEGIN\na\nBEGIN\nb\nEND\nc\n"), vec![2..=u64::MAX]);
+    }
+
+    #[test]
+    fn test_region_tracker() {
+        let regions = Regions::default().lines(3..=10);
+        let mut tracker = regions.tracker(b"").unwrap();
+        assert!(!tracker.contains(2));
+        assert!(tracker.contains(3));
+        assert!(tracker.contains(10));
+        assert!(tracker.is_past_end(11));
+
+        let mut tracker = RegionTracker {
+            ranges: vec![2..=3, 6..=7],
+            next: 0,
+        };
+        assert!(!tracker.contains(1));
+        assert!(tracker.contains(2));
+        assert!(!tracker.contains(5));
+        assert!(tracker.contains(7));
+        assert!(!tracker.is_past_end(7));
+        assert!(tracker.is_past_end(8));
+    }
+}
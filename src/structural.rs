@@ -0,0 +1,440 @@
+//! Structural patterns, in the style of comby.
+//!
+//! A pattern is literal text with holes such as `:[args]`. A hole
+//! matches any text in which `()`, `[]` and `{}` are balanced, and
+//! skips over string literals, so `old_call(:[args])` matches calls
+//! whose arguments contain nested calls or span several lines.
+//! Whitespace in a pattern matches any whitespace, and the rest
+//! matches case sensitively.
+//!
+//! Files aren't parsed per language, so single quotes are only taken
+//! as a character literal like `'('`, since in Rust they're more
+//! often a lifetime. A bracket inside a longer single-quoted string,
+//! as in JavaScript or Python, is counted like any other, so
+//! `foo(:[x])` matches only `foo('a)` in `foo('a)b')`.
+
+use anyhow::{bail, Result};
+use grep::matcher::{Captures, Match, Matcher, NoError};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(Vec<u8>),
+    Space,
+    /// A hole, by capture index.
+    Hole(usize),
+}
+
+#[derive(Debug)]
+pub struct StructuralMatcher {
+    pieces: Vec<Piece>,
+    /// Hole names, where capture index `i` is named `names[i - 1]`.
+    names: Vec<String>,
+}
+
+/// Anonymous holes don't have to match the same text each time.
+const ANONYMOUS_HOLE: &str = "_";
+
+impl StructuralMatcher {
+    pub fn new(pattern: &str) -> Result<StructuralMatcher> {
+        let mut pieces = vec![];
+        let mut names: Vec<String> = vec![];
+        let mut literal = vec![];
+
+        let mut rest = pattern;
+        while let Some(c) = rest.chars().next() {
+            if let Some(hole) = rest.strip_prefix(":[") {
+                let end = match hole.find(']') {
+                    Some(end) => end,
+                    None => bail!("unclosed hole in '{}'", rest),
+                };
+
+                let name = &hole[..end];
+                if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    bail!("invalid hole name ':[{}]'", name);
+                }
+
+                let index = match names.iter().position(|n| n == name) {
+                    Some(i) if name != ANONYMOUS_HOLE => i + 1,
+                    _ => {
+                        names.push(name.to_owned());
+                        names.len()
+                    }
+                };
+
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Hole(index));
+                rest = &hole[end + 1..];
+            } else if c.is_whitespace() {
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                if pieces.last() != Some(&Piece::Space) {
+                    pieces.push(Piece::Space);
+                }
+                rest = rest.trim_start();
+            } else {
+                literal.extend_from_slice(&rest.as_bytes()[..c.len_utf8()]);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+
+        if !matches!(pieces.first(), Some(Piece::Literal(_))) {
+            bail!("structural patterns must start with text, not a hole or whitespace");
+        }
+
+        Ok(StructuralMatcher { pieces, names })
+    }
+
+    fn find_captures(
+        &self,
+        haystack: &[u8],
+        at: usize,
+        caps: &mut [Option<Match>],
+    ) -> Option<Match> {
+        let first = match &self.pieces[0] {
+            Piece::Literal(literal) => literal,
+            _ => unreachable!("patterns start with a literal"),
+        };
+
+        let mut start = at;
+        while let Some(i) = find(haystack, start, first) {
+            caps.iter_mut().for_each(|cap| *cap = None);
+
+            let is_word_start = i == 0 || !is_word(haystack[i - 1]) || !is_word(first[0]);
+            if is_word_start {
+                if let Some(end) = self.match_pieces(haystack, 0, i, caps) {
+                    let m = Match::new(i, end);
+                    caps[0] = Some(m);
+                    return Some(m);
+                }
+            }
+
+            start = i + 1;
+        }
+
+        None
+    }
+
+    /// Match `self.pieces[idx..]` at `pos`, returning where the match
+    /// ends.
+    fn match_pieces(
+        &self,
+        haystack: &[u8],
+        idx: usize,
+        pos: usize,
+        caps: &mut [Option<Match>],
+    ) -> Option<usize> {
+        let piece = match self.pieces.get(idx) {
+            Some(piece) => piece,
+            None => {
+                // Don't end a match in the middle of a word.
+                let ends_word =
+                    pos == haystack.len() || !is_word(haystack[pos]) || !is_word(haystack[pos - 1]);
+                return Some(pos).filter(|_| ends_word);
+            }
+        };
+
+        match piece {
+            Piece::Literal(literal) => {
+                if haystack[pos..].starts_with(literal) {
+                    self.match_pieces(haystack, idx + 1, pos + literal.len(), caps)
+                } else {
+                    None
+                }
+            }
+
+            Piece::Space => {
+                let len = haystack[pos..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_whitespace())
+                    .count();
+
+                // Whitespace is only optional where it doesn't separate
+                // words, so `a b` doesn't match `ab`.
+                let separates_words = pos > 0
+                    && is_word(haystack[pos - 1])
+                    && haystack.get(pos + len).is_some_and(|&b| is_word(b));
+                if len == 0 && separates_words {
+                    return None;
+                }
+
+                self.match_pieces(haystack, idx + 1, pos + len, caps)
+            }
+
+            Piece::Hole(index) => {
+                if let Some(bound) = caps[*index] {
+                    // A repeated hole must match the same text again.
+                    let text = &haystack[bound];
+                    return if haystack[pos..].starts_with(text) {
+                        self.match_pieces(haystack, idx + 1, pos + text.len(), caps)
+                    } else {
+                        None
+                    };
+                }
+
+                let is_last = idx + 1 == self.pieces.len();
+                let mut end = pos;
+                loop {
+                    if !is_last {
+                        caps[*index] = Some(Match::new(pos, end));
+                        if let Some(match_end) = self.match_pieces(haystack, idx + 1, end, caps) {
+                            return Some(match_end);
+                        }
+                        caps[*index] = None;
+                    }
+
+                    // A hole at the end of a pattern runs to the end of
+                    // the line, or of the group it's in.
+                    let stops_here =
+                        matches!(haystack.get(end), None | Some(b'\n' | b')' | b']' | b'}'));
+                    if is_last && stops_here {
+                        caps[*index] = Some(Match::new(pos, end));
+                        return Some(end);
+                    }
+
+                    end = skip_balanced(haystack, end)?;
+                }
+            }
+        }
+    }
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+fn find(haystack: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| from + i)
+}
+
+/// Skip the balanced unit of text starting at `pos`: a bracketed
+/// group, a string literal, or a single byte. Returns `None` at the
+/// end of input, an unbalanced closing bracket, or an unterminated
+/// group.
+fn skip_balanced(haystack: &[u8], pos: usize) -> Option<usize> {
+    let close = match haystack.get(pos)? {
+        b'(' => b')',
+        b'[' => b']',
+        b'{' => b'}',
+        b')' | b']' | b'}' => return None,
+        b'"' => return skip_string(haystack, pos, b'"'),
+        b'\'' => return Some(skip_char_literal(haystack, pos)),
+        _ => return Some(pos + 1),
+    };
+
+    let mut i = pos + 1;
+    loop {
+        if *haystack.get(i)? == close {
+            return Some(i + 1);
+        }
+        i = skip_balanced(haystack, i)?;
+    }
+}
+
+fn skip_string(haystack: &[u8], pos: usize, quote: u8) -> Option<usize> {
+    let mut i = pos + 1;
+    while i < haystack.len() {
+        match haystack[i] {
+            b'\\' => i += 2,
+            b if b == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+/// Single quotes are only treated as a character literal like `'('`
+/// or `'\n'`, as they're too often something else, like a lifetime.
+fn skip_char_literal(haystack: &[u8], pos: usize) -> usize {
+    let rest = &haystack[pos + 1..];
+    let len = match rest.first() {
+        Some(b'\\') => rest.iter().take(12).position(|&b| b == b'\''),
+        Some(&first) => {
+            // The length of a UTF-8 character, from its first byte.
+            let char_len = match first {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            Some(char_len).filter(|&len| rest.get(len) == Some(&b'\''))
+        }
+        None => None,
+    };
+
+    match len {
+        Some(len) => pos + 1 + len + 1,
+        None => pos + 1,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructuralCaptures(Vec<Option<Match>>);
+
+impl Captures for StructuralCaptures {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, i: usize) -> Option<Match> {
+        self.0.get(i).copied().flatten()
+    }
+}
+
+impl Matcher for StructuralMatcher {
+    type Captures = StructuralCaptures;
+    type Error = NoError;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, NoError> {
+        let mut caps = vec![None; self.capture_count()];
+        Ok(self.find_captures(haystack, at, &mut caps))
+    }
+
+    fn new_captures(&self) -> Result<StructuralCaptures, NoError> {
+        Ok(StructuralCaptures(vec![None; self.capture_count()]))
+    }
+
+    fn capture_count(&self) -> usize {
+        self.names.len() + 1
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name).map(|i| i + 1)
+    }
+
+    fn captures_at(
+        &self,
+        haystack: &[u8],
+        at: usize,
+        caps: &mut StructuralCaptures,
+    ) -> Result<bool, NoError> {
+        Ok(self.find_captures(haystack, at, &mut caps.0).is_some())
+    }
+}
+
+/// Rewrite a replacement with `:[name]` holes as a template for
+/// `Captures::interpolate`.
+pub fn replacement_template(replacement: &str) -> String {
+    let mut template = String::new();
+
+    let mut rest = replacement;
+    while let Some(c) = rest.chars().next() {
+        let hole = rest
+            .strip_prefix(":[")
+            .and_then(|hole| Some(&hole[..hole.find(']')?]));
+
+        match hole {
+            Some(name) => {
+                template.push_str(&format!("${{{}}}", name));
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                if c == '$' {
+                    template.push('$');
+                }
+                template.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    template
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, haystack: &str) -> Option<Vec<String>> {
+        let matcher = StructuralMatcher::new(pattern).unwrap();
+        let mut caps = matcher.new_captures().unwrap();
+        if !matcher.captures(haystack.as_bytes(), &mut caps).unwrap() {
+            return None;
+        }
+
+        Some(
+            (0..caps.len())
+                .map(|i| caps.get(i).map_or("", |m| &haystack[m]).to_owned())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_balanced_holes() {
+        assert_eq!(
+            captures("foo(:[args])", "x = foo(bar(1), [2, 3]) + 1"),
+            Some(vec!["foo(bar(1), [2, 3])".into(), "bar(1), [2, 3]".into()])
+        );
+        assert_eq!(
+            captures("foo(:[a], :[b])", "foo(f(x, y),\n    \"),\")"),
+            Some(vec![
+                "foo(f(x, y),\n    \"),\")".into(),
+                "f(x, y)".into(),
+                "\"),\"".into()
+            ])
+        );
+        assert_eq!(
+            captures("foo(:[a])", "foo(')')"),
+            Some(vec!["foo(')')".into(), "')'".into()])
+        );
+        assert_eq!(captures("foo(:[a])", "foo(bar"), None);
+    }
+
+    #[test]
+    fn test_single_quoted_strings() {
+        // Only character literals are skipped, as documented.
+        assert_eq!(
+            captures("foo(:[a])", "foo('a)b')"),
+            Some(vec!["foo('a)".into(), "'a".into()])
+        );
+        assert_eq!(
+            captures("foo(:[a])", "foo(x: &'a str)"),
+            Some(vec!["foo(x: &'a str)".into(), "x: &'a str".into()])
+        );
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        assert_eq!(captures("foo(:[a])", "my_foo(1)"), None);
+        assert_eq!(captures("return :[x]", "return_value(1)"), None);
+        assert_eq!(
+            captures("return :[x]", "  return a + b\nfoo"),
+            Some(vec!["return a + b".into(), "a + b".into()])
+        );
+        assert_eq!(
+            captures("return :[x]", "{ return f(a) }"),
+            Some(vec!["return f(a) ".into(), "f(a) ".into()])
+        );
+    }
+
+    #[test]
+    fn test_repeated_holes() {
+        assert!(StructuralMatcher::new(":[a] == :[a]").is_err());
+        assert_eq!(
+            captures("eq(:[a], :[a])", "eq(x, y) || eq(z, z)"),
+            Some(vec!["eq(z, z)".into(), "z".into()])
+        );
+        assert_eq!(
+            captures("f(:[_], :[_])", "f(x, y)"),
+            Some(vec!["f(x, y)".into(), "x".into(), "y".into()])
+        );
+    }
+
+    #[test]
+    fn test_replacement_template() {
+        assert_eq!(
+            replacement_template("new_call(:[args], $None)"),
+            "new_call(${args}, $$None)"
+        );
+    }
+}
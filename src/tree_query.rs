@@ -0,0 +1,321 @@
+//! Searching with tree-sitter queries, so that only the syntax nodes
+//! a query matches are replaced.
+//!
+//! Each match of the query replaces the text spanned by its captured
+//! nodes with the template, where `$name` or `${name}` is the text of
+//! the node captured as `@name`.
+
+use std::cmp::Reverse;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tree_sitter::{Language, Parser, Query, QueryCapture, QueryCursor, StreamingIterator};
+
+use crate::search::{Line, Match};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Grammar {
+    Rust,
+    TypeScript,
+    Tsx,
+}
+
+impl Grammar {
+    const ALL: [Grammar; 3] = [Grammar::Rust, Grammar::TypeScript, Grammar::Tsx];
+
+    fn for_path(path: &Path) -> Option<Grammar> {
+        match path.extension()?.to_str()? {
+            "rs" => Some(Grammar::Rust),
+            "ts" | "mts" | "cts" => Some(Grammar::TypeScript),
+            "tsx" => Some(Grammar::Tsx),
+            _ => None,
+        }
+    }
+
+    fn language(self) -> Language {
+        match self {
+            Grammar::Rust => tree_sitter_rust::LANGUAGE.into(),
+            Grammar::TypeScript => tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+            Grammar::Tsx => tree_sitter_typescript::LANGUAGE_TSX.into(),
+        }
+    }
+}
+
+/// A text replacement within a file.
+struct Edit {
+    range: Range<usize>,
+    replacement: String,
+}
+
+#[derive(Debug)]
+pub struct TreeQuery {
+    /// The query, compiled for each grammar it's valid in.
+    queries: Vec<(Grammar, Query)>,
+    template: String,
+    before_context: usize,
+    after_context: usize,
+}
+
+impl TreeQuery {
+    pub fn new(
+        query: &str,
+        template: &str,
+        before_context: usize,
+        after_context: usize,
+    ) -> Result<TreeQuery> {
+        // Node types differ between languages, so a query usually
+        // only makes sense in some of them.
+        let mut queries = vec![];
+        let mut errors = vec![];
+        for grammar in Grammar::ALL {
+            match Query::new(&grammar.language(), query) {
+                Ok(query) => queries.push((grammar, query)),
+                Err(err) => errors.push(format!("{:?}: {}", grammar, err)),
+            }
+        }
+
+        if queries.is_empty() {
+            bail!("invalid query for every language:\n{}", errors.join("\n"));
+        }
+
+        Ok(TreeQuery {
+            queries,
+            template: template.to_owned(),
+            before_context,
+            after_context,
+        })
+    }
+
+    /// Search `path` for matches of the query, stopping once
+    /// `max_matches` have been found. Files in other languages have
+    /// no matches.
+    pub fn search_path(&self, path: &Path, max_matches: usize) -> Result<Vec<Match>> {
+        let query = Grammar::for_path(path).and_then(|grammar| {
+            self.queries
+                .iter()
+                .find(|(g, _)| *g == grammar)
+                .map(|(_, query)| (grammar, query))
+        });
+        let (grammar, query) = match query {
+            Some(query) => query,
+            None => return Ok(vec![]),
+        };
+
+        let contents = fs::read(path)?;
+
+        let mut parser = Parser::new();
+        parser.set_language(&grammar.language())?;
+        let tree = parser
+            .parse(&contents, None)
+            .context("failed to parse file")?;
+
+        let mut edits = vec![];
+        let mut cursor = QueryCursor::new();
+        let mut query_matches = cursor.matches(query, tree.root_node(), contents.as_slice());
+        while let Some(query_match) = query_matches.next() {
+            let captures = query_match.captures();
+            let start = captures.iter().map(|c| c.node.start_byte()).min();
+            let end = captures.iter().map(|c| c.node.end_byte()).max();
+
+            if let (Some(start), Some(end)) = (start, end) {
+                edits.push(Edit {
+                    range: start..end,
+                    replacement: self.interpolate(query, captures, &contents),
+                });
+            }
+        }
+
+        // Where matches overlap, such as nested calls, only the
+        // outermost is replaced.
+        edits.sort_by_key(|edit| (edit.range.start, Reverse(edit.range.end)));
+        let mut replaced_up_to = 0;
+        edits.retain(|edit| {
+            let is_disjoint = edit.range.start >= replaced_up_to;
+            if is_disjoint {
+                replaced_up_to = edit.range.end;
+            }
+            is_disjoint
+        });
+
+        let mut matches = self.matches_for_edits(&contents, edits);
+        matches.truncate(max_matches);
+        Ok(matches)
+    }
+
+    fn interpolate(&self, query: &Query, captures: &[QueryCapture<'_>], contents: &[u8]) -> String {
+        let capture_text = |name: &str| {
+            let index = query.capture_names().iter().position(|n| *n == name)?;
+            let capture = captures.iter().find(|c| c.index as usize == index)?;
+            Some(String::from_utf8_lossy(
+                &contents[capture.node.byte_range()],
+            ))
+        };
+
+        let mut replacement = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(i) = rest.find('$') {
+            replacement.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            let name = if let Some(braced) = rest.strip_prefix('{') {
+                braced.find('}').map(|end| (&braced[..end], end + 2))
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                Some((&rest[..end], end)).filter(|_| end > 0)
+            };
+
+            match name {
+                Some((name, len)) => {
+                    replacement.push_str(&capture_text(name).unwrap_or_default());
+                    rest = &rest[len..];
+                }
+                // `$$` and a `$` not followed by a name are literal.
+                None => {
+                    replacement.push('$');
+                    rest = rest.strip_prefix('$').unwrap_or(rest);
+                }
+            }
+        }
+        replacement.push_str(rest);
+
+        replacement
+    }
+
+    /// Group edits into matches of the whole lines they touch, so
+    /// they can be shown and applied like any other match.
+    fn matches_for_edits(&self, contents: &[u8], edits: Vec<Edit>) -> Vec<Match> {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                contents
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, &b)| b == b'\n' && i + 1 < contents.len())
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        let line_of = |offset: usize| line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_range = |first: usize, last: usize| {
+            let end = line_starts.get(last + 1).copied().unwrap_or(contents.len());
+            line_starts[first]..end
+        };
+        let text = |range: Range<usize>| String::from_utf8_lossy(&contents[range]).into_owned();
+
+        // Edits on the same lines make up one match.
+        let mut groups: Vec<(usize, usize, Vec<Edit>)> = vec![];
+        for edit in edits {
+            let first = line_of(edit.range.start);
+            let last = line_of(edit.range.end.saturating_sub(1).max(edit.range.start));
+
+            match groups.last_mut() {
+                Some((_, group_last, group)) if first <= *group_last => {
+                    *group_last = (*group_last).max(last);
+                    group.push(edit);
+                }
+                _ => groups.push((first, last, vec![edit])),
+            }
+        }
+
+        let num_lines = line_starts.len();
+        let mut matches = vec![];
+        // Lines already shown as context of the previous match.
+        let mut shown_up_to = 0;
+        for (i, (first, last, group)) in groups.iter().enumerate() {
+            let range = line_range(*first, *last);
+
+            let mut replacement = String::new();
+            let mut pos = range.start;
+            for edit in group {
+                replacement.push_str(&text(pos..edit.range.start));
+                replacement.push_str(&edit.replacement);
+                pos = edit.range.end;
+            }
+            replacement.push_str(&text(pos..range.end));
+
+            // Context doesn't overlap neighbouring matches or their
+            // context.
+            let next_first = groups.get(i + 1).map_or(num_lines, |group| group.0);
+            let line_at = |n: usize| Line(n as u64 + 1, text(line_range(n, n)));
+
+            let pre_start = first.saturating_sub(self.before_context).max(shown_up_to);
+            shown_up_to = (last + 1 + self.after_context).min(next_first);
+
+            matches.push(Match {
+                line: Line(*first as u64 + 1, text(range)),
+                context_pre: (pre_start..*first).map(line_at).collect(),
+                context_post: (last + 1..shown_up_to).map(line_at).collect(),
+                in_scope: None,
+                replacement: Some(replacement),
+            });
+        }
+
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn search(query: &str, template: &str, name: &str, contents: &str) -> Vec<(u64, String)> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+
+        TreeQuery::new(query, template, 0, 0)
+            .unwrap()
+            .search_path(&path, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.line.0, m.replacement.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rust_calls() {
+        let query = r#"(call_expression
+            function: (identifier) @name (#eq? @name "foo")
+            arguments: (arguments) @args)"#;
+        let contents = "\
+fn main() {
+    // foo(1) is only text here
+    let foo = foo(1, bar(2));
+    println!(\"foo(3)\");
+    foo(
+        4,
+    );
+}
+";
+        assert_eq!(
+            search(query, "baz$args", "a.rs", contents),
+            vec![
+                (3, "    let foo = baz(1, bar(2));\n".into()),
+                (5, "    baz(\n        4,\n    );\n".into()),
+            ]
+        );
+        assert_eq!(search(query, "baz$args", "a.txt", contents), vec![]);
+    }
+
+    #[test]
+    fn test_nested_matches() {
+        let query = r#"(call_expression function: (identifier) @f (#eq? @f "f") arguments: (arguments) @args)"#;
+        assert_eq!(
+            search(query, "g${args}", "a.ts", "f(f(1));\n"),
+            vec![(1, "g(f(1));\n".into())]
+        );
+    }
+
+    #[test]
+    fn test_invalid_query() {
+        assert!(TreeQuery::new("(no_such_node) @x", "", 0, 0).is_err());
+        assert!(TreeQuery::new("(macro_invocation) @x", "", 0, 0).is_ok());
+    }
+}
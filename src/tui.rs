@@ -0,0 +1,465 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::search::{self, Match};
+
+/// A file whose matches are being reviewed.
+pub struct ReviewFile {
+    pub path: PathBuf,
+    matches: Vec<ReviewMatch>,
+}
+
+struct ReviewMatch {
+    search_match: Match,
+    replacement: String,
+    accepted: bool,
+}
+
+impl ReviewFile {
+    /// Matches start out accepted, and are toggled off during review.
+    pub fn new(path: PathBuf, matches: Vec<(Match, String)>) -> ReviewFile {
+        let matches = matches
+            .into_iter()
+            .map(|(search_match, replacement)| ReviewMatch {
+                search_match,
+                replacement,
+                accepted: true,
+            })
+            .collect();
+
+        ReviewFile { path, matches }
+    }
+
+    pub fn num_matches(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Consume the file, returning accepted matches and their
+    /// replacements in line order.
+    pub fn into_accepted(self) -> Vec<(Match, String)> {
+        self.matches
+            .into_iter()
+            .filter(|m| m.accepted)
+            .map(|m| (m.search_match, m.replacement))
+            .collect()
+    }
+
+    fn num_accepted(&self) -> usize {
+        self.matches.iter().filter(|m| m.accepted).count()
+    }
+
+    fn set_all(&mut self, accepted: bool) {
+        for m in &mut self.matches {
+            m.accepted = accepted;
+        }
+    }
+}
+
+/// Open a full screen review of `files`.
+///
+/// Returns the files with the user's decisions once they choose to
+/// write, or `None` if they quit without writing.
+pub fn review(files: Vec<ReviewFile>) -> Result<Option<Vec<ReviewFile>>> {
+    if files.is_empty() {
+        return Ok(Some(files));
+    }
+
+    let mut terminal = ratatui::init();
+    let result = Review::new(files).run(&mut terminal);
+    ratatui::restore();
+
+    result
+}
+
+struct Review {
+    files: Vec<ReviewFile>,
+    file_idx: usize,
+    match_idx: usize,
+    file_list: ListState,
+}
+
+/// What to do after a key press.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Continue,
+    Write,
+    Quit,
+}
+
+const KEY_HELP: &str = "↑↓ match  ←→ file  space toggle  y/n accept/reject  \
+                        a/d file  A/D all  w write  q quit";
+
+impl Review {
+    fn new(files: Vec<ReviewFile>) -> Review {
+        Review {
+            files,
+            file_idx: 0,
+            match_idx: 0,
+            file_list: ListState::default(),
+        }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<Option<Vec<ReviewFile>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            match self.handle_key(key) {
+                Action::Continue => {}
+                Action::Write => return Ok(Some(self.files)),
+                Action::Quit => return Ok(None),
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Action::Quit
+            }
+            KeyCode::Char('w') => return Action::Write,
+
+            KeyCode::Down | KeyCode::Char('j') => self.next_match(),
+            KeyCode::Up | KeyCode::Char('k') => self.prev_match(),
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Tab => self.next_file(),
+            KeyCode::Left | KeyCode::Char('h') | KeyCode::BackTab => self.prev_file(),
+
+            KeyCode::Char(' ') => {
+                let m = self.current_match();
+                m.accepted = !m.accepted;
+            }
+            KeyCode::Char('y') => {
+                self.current_match().accepted = true;
+                self.next_match();
+            }
+            KeyCode::Char('n') => {
+                self.current_match().accepted = false;
+                self.next_match();
+            }
+            KeyCode::Char('a') => self.files[self.file_idx].set_all(true),
+            KeyCode::Char('d') => self.files[self.file_idx].set_all(false),
+            KeyCode::Char('A') => self.files.iter_mut().for_each(|f| f.set_all(true)),
+            KeyCode::Char('D') => self.files.iter_mut().for_each(|f| f.set_all(false)),
+
+            _ => {}
+        }
+
+        Action::Continue
+    }
+
+    fn current_match(&mut self) -> &mut ReviewMatch {
+        &mut self.files[self.file_idx].matches[self.match_idx]
+    }
+
+    fn next_match(&mut self) {
+        if self.match_idx + 1 < self.files[self.file_idx].num_matches() {
+            self.match_idx += 1;
+        } else if self.file_idx + 1 < self.files.len() {
+            self.next_file();
+        }
+    }
+
+    fn prev_match(&mut self) {
+        if self.match_idx > 0 {
+            self.match_idx -= 1;
+        } else if self.file_idx > 0 {
+            self.prev_file();
+            self.match_idx = self.files[self.file_idx].num_matches() - 1;
+        }
+    }
+
+    fn next_file(&mut self) {
+        if self.file_idx + 1 < self.files.len() {
+            self.file_idx += 1;
+            self.match_idx = 0;
+        }
+    }
+
+    fn prev_file(&mut self) {
+        if self.file_idx > 0 {
+            self.file_idx -= 1;
+            self.match_idx = 0;
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [files_area, diff_area] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main_area);
+
+        self.draw_files(frame, files_area);
+        self.draw_diff(frame, diff_area);
+        self.draw_status(frame, status_area);
+    }
+
+    fn draw_files(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .files
+            .iter()
+            .map(|file| {
+                ListItem::new(format!(
+                    "[{}/{}] {}",
+                    file.num_accepted(),
+                    file.num_matches(),
+                    file.path.display()
+                ))
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(Block::bordered().title("Files"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        self.file_list.select(Some(self.file_idx));
+        frame.render_stateful_widget(list, area, &mut self.file_list);
+    }
+
+    fn draw_diff(&self, frame: &mut Frame, area: Rect) {
+        let file = &self.files[self.file_idx];
+
+        let mut lines = vec![];
+        let mut selected_line = 0;
+        let mut last_line_num = None;
+
+        for (idx, m) in file.matches.iter().enumerate() {
+            let search_match = &m.search_match;
+            let first_line_num = search_match
+                .context_pre
+                .first()
+                .map_or(search_match.line.0, |line| line.0);
+
+            if last_line_num.is_some_and(|last| first_line_num > last + 1) {
+                lines.push(Line::styled(
+                    "       ---",
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+
+            for line in &search_match.context_pre {
+                lines.push(context_line(line));
+            }
+
+            let is_selected = idx == self.match_idx;
+            if is_selected {
+                selected_line = lines.len();
+            }
+
+            let gutter = format!(
+                "{} [{}] ",
+                if is_selected { ">" } else { " " },
+                if m.accepted { "x" } else { " " }
+            );
+            let gutter_style = if is_selected {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            let replacement_style = if m.accepted {
+                Style::default().fg(Color::Green)
+            } else {
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::CROSSED_OUT)
+            };
+
+            lines.push(Line::from(vec![
+                Span::styled(gutter, gutter_style),
+                Span::styled(
+                    format!(
+                        "-{:4} {}",
+                        search_match.line.0,
+                        display_text(&search_match.line.1)
+                    ),
+                    Style::default().fg(Color::Red),
+                ),
+            ]));
+            lines.push(Line::from(vec![
+                Span::raw("      "),
+                Span::styled(
+                    format!(
+                        "+{:4} {}",
+                        search_match.line.0,
+                        display_text(&m.replacement)
+                    ),
+                    replacement_style,
+                ),
+            ]));
+
+            for line in &search_match.context_post {
+                lines.push(context_line(line));
+            }
+
+            last_line_num = Some(
+                search_match
+                    .context_post
+                    .last()
+                    .map_or(search_match.line.0, |line| line.0),
+            );
+        }
+
+        // Keep the selected match in the upper part of the pane.
+        let height = area.height.saturating_sub(2) as usize;
+        let scroll = selected_line.saturating_sub(height / 3);
+
+        let diff = Paragraph::new(lines)
+            .block(Block::bordered().title(file.path.display().to_string()))
+            .scroll((scroll.try_into().unwrap_or(u16::MAX), 0));
+
+        frame.render_widget(diff, area);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let num_accepted: usize = self.files.iter().map(|f| f.num_accepted()).sum();
+        let num_matches: usize = self.files.iter().map(|f| f.num_matches()).sum();
+
+        let status = Paragraph::new(format!(
+            " {}/{} accepted  {}",
+            num_accepted, num_matches, KEY_HELP
+        ))
+        .style(Style::default().add_modifier(Modifier::REVERSED));
+
+        frame.render_widget(status, area);
+    }
+}
+
+fn context_line(line: &search::Line) -> Line<'static> {
+    Line::raw(format!("       {:4} {}", line.0, display_text(&line.1)))
+}
+
+/// Lines keep their line terminator, and tabs aren't rendered.
+fn display_text(text: &str) -> String {
+    text.trim_end_matches(['\n', '\r']).replace('\t', "    ")
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use super::*;
+
+    fn review_file(path: &str, lines: &[u64]) -> ReviewFile {
+        let matches = lines
+            .iter()
+            .map(|&line_num| {
+                let search_match = Match {
+                    line: search::Line(line_num, "foo\n".to_owned()),
+                    context_pre: vec![],
+                    context_post: vec![],
+                    in_scope: None,
+                    replacement: None,
+                };
+                (search_match, "bar\n".to_owned())
+            })
+            .collect();
+
+        ReviewFile::new(PathBuf::from(path), matches)
+    }
+
+    fn press(review: &mut Review, keys: &str) -> Action {
+        let mut action = Action::Continue;
+        for c in keys.chars() {
+            action = review.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        action
+    }
+
+    fn accepted_lines(file: ReviewFile) -> Vec<u64> {
+        file.into_accepted()
+            .iter()
+            .map(|(search_match, _)| search_match.line.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut review = Review::new(vec![review_file("a", &[1, 2]), review_file("b", &[3])]);
+
+        press(&mut review, "j");
+        assert_eq!((review.file_idx, review.match_idx), (0, 1));
+
+        // Moving past the last match of a file goes on to the next.
+        press(&mut review, "j");
+        assert_eq!((review.file_idx, review.match_idx), (1, 0));
+        press(&mut review, "j");
+        assert_eq!((review.file_idx, review.match_idx), (1, 0));
+
+        // Moving back goes to the last match of the previous file.
+        press(&mut review, "k");
+        assert_eq!((review.file_idx, review.match_idx), (0, 1));
+
+        press(&mut review, "l");
+        assert_eq!((review.file_idx, review.match_idx), (1, 0));
+        press(&mut review, "h");
+        assert_eq!((review.file_idx, review.match_idx), (0, 0));
+        press(&mut review, "h");
+        assert_eq!((review.file_idx, review.match_idx), (0, 0));
+    }
+
+    #[test]
+    fn test_toggles() {
+        let mut review = Review::new(vec![review_file("a", &[1, 2, 3]), review_file("b", &[4])]);
+
+        // Reject the first match, then toggle the second off and on.
+        press(&mut review, "n  ");
+        assert_eq!(review.files[0].num_accepted(), 2);
+        press(&mut review, " y");
+        assert_eq!(review.files[0].num_accepted(), 2);
+
+        press(&mut review, "D");
+        assert_eq!(review.files[0].num_accepted(), 0);
+        assert_eq!(review.files[1].num_accepted(), 0);
+
+        press(&mut review, "la");
+        assert_eq!(review.files[0].num_accepted(), 0);
+        assert_eq!(review.files[1].num_accepted(), 1);
+
+        assert_eq!(press(&mut review, "w"), Action::Write);
+        let mut files = review.files.into_iter();
+        assert_eq!(accepted_lines(files.next().unwrap()), Vec::<u64>::new());
+        assert_eq!(accepted_lines(files.next().unwrap()), vec![4]);
+    }
+
+    #[test]
+    fn test_quit() {
+        let mut review = Review::new(vec![review_file("a", &[1])]);
+        assert_eq!(press(&mut review, "q"), Action::Quit);
+
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(review.handle_key(ctrl_c), Action::Quit);
+        assert_eq!(press(&mut review, "c"), Action::Continue);
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut review = Review::new(vec![review_file("a", &[1, 5]), review_file("b", &[3])]);
+        press(&mut review, "n");
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 10)).unwrap();
+        terminal.draw(|frame| review.draw(frame)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("[1/2] a"));
+        assert!(text.contains("2/3 accepted"));
+    }
+
+    #[test]
+    fn test_display_text() {
+        assert_eq!(display_text("\tfoo\r\n"), "    foo");
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use std::fs::{read_to_string, File};
 use std::io::Write;
 
@@ -180,11 +182,12 @@ fn test_simple_replace_ignore_hidden() {
         (".ignore", "vcs-hidden"),
     ]);
 
+    // Nothing matched
     Command::cargo_bin("fnr")
         .unwrap()
         .args(&["foo", "bar", "--write", test_dir.path().to_str().unwrap()])
         .assert()
-        .success();
+        .code(1);
 
     assert_eq!(
         read_to_string(test_dir.path().join(".hidden_file")).unwrap(),
@@ -353,3 +356,1327 @@ fn test_replace_at_word_boundary() {
         "bar\nfooBar\n(bar)\nfoob\n"
     );
 }
+
+#[test]
+fn test_swap() {
+    let orig_content = "min(max)\nminimum\nleft\n";
+    let test_dir = create_test_files(&[("a", orig_content)]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--swap",
+            "min",
+            "max",
+            "--word",
+            "--write",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "max(min)\nminimum\nleft\n"
+    );
+}
+
+#[test]
+fn test_swap_is_literal() {
+    let orig_content = "max\\d max1 min\n";
+    let test_dir = create_test_files(&[("a", orig_content)]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--swap",
+            "max\\d",
+            "min",
+            "--write",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "min max1 max\\d\n"
+    );
+}
+
+#[test]
+fn test_swap_overlapping_sides() {
+    let test_dir = create_test_files(&[("a", "min minimum\n"), ("b", "ab a\n")]);
+
+    for (path, left, right) in [("a", "min", "minimum"), ("b", "a", "ab")] {
+        Command::cargo_bin("fnr")
+            .unwrap()
+            .args(&[
+                "--swap",
+                left,
+                right,
+                "--write",
+                test_dir.path().join(path).to_str().unwrap(),
+            ])
+            .assert()
+            .success();
+    }
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "minimum min\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "a ab\n");
+}
+
+#[test]
+fn test_rename_paths() {
+    let test_dir = create_test_files(&[
+        ("widget_view.rs", "widget\n"),
+        ("widget.rs", "widget\n"),
+        ("gadget.rs", "gadget\n"),
+    ]);
+    std::fs::create_dir(test_dir.path().join("widget")).unwrap();
+    File::create(test_dir.path().join("widget").join("widget.txt")).unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "widget",
+            "gadget",
+            "--rename-paths",
+            "--write",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("gadget_view.rs")).unwrap(),
+        "gadget\n"
+    );
+    assert!(test_dir.path().join("gadget").join("gadget.txt").exists());
+    assert!(!test_dir.path().join("widget").exists());
+
+    // Renaming would collide with an existing file.
+    assert_eq!(
+        read_to_string(test_dir.path().join("widget.rs")).unwrap(),
+        "gadget\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("gadget.rs")).unwrap(),
+        "gadget\n"
+    );
+}
+
+#[test]
+fn test_sort_path() {
+    let names: Vec<String> = (0..20).map(|i| format!("{:02}.txt", i)).collect();
+    let files: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "foo\n")).collect();
+    let test_dir = create_test_files(&files);
+
+    let output = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "foo",
+            "bar",
+            "--dry-run",
+            "--sort=path",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.split_once(".txt"))
+        .filter_map(|(path, _)| path.rsplit('/').next())
+        .collect();
+
+    let expected: Vec<String> = (0..20).map(|i| format!("{:02}", i)).collect();
+    assert_eq!(printed, expected);
+}
+
+#[test]
+fn test_sort_modified() {
+    let test_dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(test_dir.path().join("a")).unwrap();
+    let now = std::time::SystemTime::now();
+    for (name, age) in [("a/x.txt", 1), ("a/y.txt", 3), ("b.txt", 2)] {
+        let mut file = File::create(test_dir.path().join(name)).unwrap();
+        file.write_all(b"foo\n").unwrap();
+        file.set_modified(now - std::time::Duration::from_secs(age * 60))
+            .unwrap();
+    }
+
+    let output = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "foo",
+            "bar",
+            "--dry-run",
+            "--compact",
+            "--sort=modified",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Files are sorted across directories, not within each one.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.contains(":1-"))
+        .filter_map(|line| line.split_once(".txt"))
+        .filter_map(|(path, _)| path.rsplit('/').next())
+        .collect();
+    assert_eq!(printed, vec!["y", "b", "x"]);
+}
+
+#[test]
+fn test_max_count_and_total() {
+    let files = [
+        ("a.txt", "foo\nfoo\nfoo\n"),
+        ("b.txt", "foo\nfoo\nfoo\n"),
+        ("c.txt", "foo\nfoo\nfoo\n"),
+    ];
+
+    let test_dir = create_test_files(&files);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--write", "--max-count=2"])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    for (name, _) in &files {
+        assert_eq!(
+            read_to_string(test_dir.path().join(name)).unwrap(),
+            "bar\nbar\nfoo\n"
+        );
+    }
+
+    // Sorted, the budget is spent on the first files.
+    let test_dir = create_test_files(&files);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "foo",
+            "bar",
+            "--write",
+            "-m2",
+            "--max-total=3",
+            "--sort=path",
+        ])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    let read = |name| read_to_string(test_dir.path().join(name)).unwrap();
+    assert_eq!(read("a.txt"), "bar\nbar\nfoo\n");
+    assert_eq!(read("b.txt"), "bar\nfoo\nfoo\n");
+    assert_eq!(read("c.txt"), "foo\nfoo\nfoo\n");
+
+    // Unsorted, the budget still holds across threads.
+    let test_dir = create_test_files(&files);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--write", "--max-total=5"])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    let num_replaced: usize = files
+        .iter()
+        .map(|(name, _)| read_to_string(test_dir.path().join(name)).unwrap())
+        .map(|contents| contents.matches("bar").count())
+        .sum();
+    assert_eq!(num_replaced, 5);
+}
+
+#[test]
+fn test_occurrence() {
+    let test_dir = create_test_files(&[("a.txt", "f(x, x, x)\nf(x)\n")]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[r"\bx\b", "y", "--write", "--occurrence=2"])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "f(x, y, x)\nf(x)\n"
+    );
+
+    let test_dir = create_test_files(&[("a.txt", "foo foo\nfoo\n"), ("b.txt", "foo\n")]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--write", "--first-in-file"])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "bar foo\nfoo\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.txt")).unwrap(),
+        "bar\n"
+    );
+}
+
+#[test]
+fn test_lines_and_between() {
+    let test_dir = create_test_files(&[("a.txt", "foo\nfoo\nfoo\nfoo\n")]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--write", "--lines=2:3"])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "foo\nbar\nbar\nfoo\n"
+    );
+
+    let contents = "\
+foo
+// BEGIN GENERATED
+foo
+// END GENERATED
+foo
+// BEGIN GENERATED
+foo
+";
+    let test_dir = create_test_files(&[("a.txt", contents)]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "foo",
+            "bar",
+            "--write",
+            "--between",
+            "BEGIN GENERATED",
+            "END GENERATED",
+        ])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "\
+foo
+// BEGIN GENERATED
+bar
+// END GENERATED
+foo
+// BEGIN GENERATED
+bar
+"
+    );
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--dry-run", "--lines=5:2"])
+        .arg(test_dir.path())
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_scope() {
+    let contents = "let foo = \"foo\"; // foo\n/* foo\n */ foo();\n";
+    let expected = [
+        ("code", "let bar = \"foo\"; // foo\n/* foo\n */ bar();\n"),
+        (
+            "comments",
+            "let foo = \"foo\"; // bar\n/* bar\n */ foo();\n",
+        ),
+        ("strings", "let foo = \"bar\"; // foo\n/* foo\n */ foo();\n"),
+    ];
+
+    for (scope, expected) in expected {
+        let test_dir = create_test_files(&[("a.rs", contents), ("b.txt", "foo\n")]);
+        Command::cargo_bin("fnr")
+            .unwrap()
+            .args(&["foo", "bar", "--write", "--scope", scope])
+            .arg(test_dir.path())
+            .assert()
+            .success();
+
+        assert_eq!(
+            read_to_string(test_dir.path().join("a.rs")).unwrap(),
+            expected
+        );
+        assert_eq!(
+            read_to_string(test_dir.path().join("b.txt")).unwrap(),
+            "foo\n"
+        );
+    }
+}
+
+#[test]
+fn test_structural() {
+    let contents = "\
+let a = old_call(f(1, 2), \")\");
+let b = old_call(
+    x,
+    [y, z],
+);
+let c = my_old_call(1);
+";
+    let test_dir = create_test_files(&[("a.rs", contents)]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--structural",
+            "old_call(:[args])",
+            "new_call(:[args], None)",
+            "--write",
+        ])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.rs")).unwrap(),
+        "\
+let a = new_call(f(1, 2), \")\", None);
+let b = new_call(
+    x,
+    [y, z],
+, None);
+let c = my_old_call(1);
+"
+    );
+
+    // Structural patterns are always case sensitive.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--structural",
+            "-i",
+            "old_call(:[args])",
+            "new_call(:[args])",
+        ])
+        .arg(test_dir.path())
+        .assert()
+        .code(2);
+}
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn test_ts_query() {
+    let contents = "\
+fn main() {
+    // foo(1)
+    let foo = foo(1, bar(2));
+    foo(
+        3,
+    );
+}
+";
+    let test_dir = create_test_files(&[("a.rs", contents), ("b.txt", "foo(1)\n")]);
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--ts-query",
+            r#"(call_expression function: (identifier) @f (#eq? @f "foo") arguments: (_) @args)"#,
+            "baz$args",
+            "--write",
+        ])
+        .arg(test_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.rs")).unwrap(),
+        "\
+fn main() {
+    // foo(1)
+    let foo = baz(1, bar(2));
+    baz(
+        3,
+    );
+}
+"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.txt")).unwrap(),
+        "foo(1)\n"
+    );
+}
+
+#[test]
+fn test_line_edits() {
+    let test_dir = create_test_files(&[
+        ("a.txt", "keep\ndrop 1\nkeep\ndrop 2\n"),
+        ("b.rs", "fn a() {}\nfn b() {}"),
+    ]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--delete-line", "drop", "--write"])
+        .arg(test_dir.path().join("a.txt"))
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "keep\nkeep\n"
+    );
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "--insert-before",
+            "#[test]",
+            "--insert-after",
+            "// end of $1",
+            r"^fn (\w+)",
+            "--write",
+        ])
+        .arg(test_dir.path().join("b.rs"))
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.rs")).unwrap(),
+        "#[test]\nfn a() {}\n// end of a\n#[test]\nfn b() {}\n// end of b"
+    );
+}
+
+#[test]
+fn test_pattern_files() {
+    let test_dir = create_test_files(&[
+        ("a.rs", "let a = \"$x\";\nold(\n    1,\n);\n"),
+        ("find.txt", "old(\n    1,\n);\n"),
+        ("quoted.txt", "\"$x\"\n"),
+    ]);
+    let path = |name| test_dir.path().join(name);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .arg("--find-file")
+        .arg(path("find.txt"))
+        .args(&["--replace-file", "-", "--literal", "--write"])
+        .arg(path("a.rs"))
+        .write_stdin("new(1, \"$$\");\n")
+        .assert()
+        .success();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .arg("--find-file")
+        .arg(path("quoted.txt"))
+        .args(&["--literal", "'y'", "--write"])
+        .arg(path("a.rs"))
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(path("a.rs")).unwrap(),
+        "let a = 'y';\nnew(1, \"$\");\n"
+    );
+
+    // Without stripping the newline, the pattern no longer matches.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .arg("--find-file")
+        .arg(path("quoted.txt"))
+        .args(&["--keep-trailing-newline", "--literal", "'y'", "--dry-run"])
+        .arg(path("a.rs"))
+        .assert()
+        .code(1);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--find-file", "-", "--replace-file", "-", "--dry-run"])
+        .arg(path("a.rs"))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_stdin_content() {
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--stdin-content", r"(\w+)@example\.com", "$1@example.org"])
+        .write_stdin("to: a@example.com\ncc: b@example.com, c@example.com\nbody")
+        .assert()
+        .success()
+        .stdout("to: a@example.org\ncc: b@example.org, c@example.org\nbody");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--stdin-content", "--delete-line", "^#"])
+        .write_stdin("# comment\nkeep\n")
+        .assert()
+        .success()
+        .stdout("keep\n");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--stdout", "foo", "bar"])
+        .write_stdin("baz\n")
+        .assert()
+        .code(1)
+        .stdout("baz\n");
+
+    // Nothing could be replaced.
+    let assert = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--stdin-content", "foo"])
+        .write_stdin("foo\n")
+        .assert()
+        .code(2)
+        .stdout("");
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("REPLACE is required"));
+
+    for flag in ["--staged", "--hidden", "--structural"] {
+        Command::cargo_bin("fnr")
+            .unwrap()
+            .args(&["--stdin-content", flag, "foo", "bar"])
+            .write_stdin("foo\n")
+            .assert()
+            .code(2)
+            .stdout("");
+    }
+}
+
+#[test]
+fn test_config_files() {
+    let test_dir = create_test_files(&[
+        ("a.txt", "foo\n"),
+        ("vendor.txt", "foo\n"),
+        ("skip.txt", "foo\n"),
+        (".fnr.toml", "exclude = [\"vendor\"]\n"),
+        ("config", "# Global defaults\n--exclude\nskip\n"),
+    ]);
+    let config_path = test_dir.path().join("config");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("FNR_CONFIG_PATH", &config_path)
+        .args(&["foo", "bar", "--write", test_dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "bar\n"
+    );
+    for file in &["vendor.txt", "skip.txt"] {
+        let contents = read_to_string(test_dir.path().join(file)).unwrap();
+        assert_eq!(contents, "foo\n");
+    }
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("FNR_CONFIG_PATH", &config_path)
+        .args(&[
+            "foo",
+            "bar",
+            "--write",
+            "--no-config",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    for file in &["vendor.txt", "skip.txt"] {
+        let contents = read_to_string(test_dir.path().join(file)).unwrap();
+        assert_eq!(contents, "bar\n");
+    }
+}
+
+#[test]
+fn test_config_files_yield_to_command_line() {
+    let test_dir = create_test_files(&[("a.txt", "foo\n"), (".fnr.toml", "context = 4\n")]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("FNR_CONFIG_PATH", "")
+        .args(&[
+            "foo",
+            "bar",
+            "--dry-run",
+            "-A",
+            "1",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_repository_config_cannot_write() {
+    let test_dir = create_test_files(&[("a.txt", "foo\n"), (".fnr.toml", "write = true\n")]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("FNR_CONFIG_PATH", "")
+        .args(&["foo", "bar", "--dry-run", test_dir.path().to_str().unwrap()])
+        .assert()
+        .code(2);
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "foo\n"
+    );
+}
+
+#[test]
+fn test_exit_codes() {
+    let test_dir = create_test_files(&[("a.txt", "foo\n")]);
+    let path = test_dir.path().to_str().unwrap();
+
+    let exit_code = |args: &[&str]| {
+        Command::cargo_bin("fnr")
+            .unwrap()
+            .args(args)
+            .arg(path)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    assert_eq!(exit_code(&["foo", "bar", "--dry-run"]), Some(0));
+    assert_eq!(exit_code(&["baz", "bar", "--dry-run"]), Some(1));
+    assert_eq!(exit_code(&["(foo", "bar", "--dry-run"]), Some(2));
+
+    assert_eq!(exit_code(&["foo", "bar", "--check"]), Some(1));
+    assert_eq!(exit_code(&["baz", "bar", "--check"]), Some(0));
+    assert_eq!(exit_code(&["foo", "foo", "--check"]), Some(0));
+    assert_eq!(exit_code(&["f(o)o", "f${1}o", "--check"]), Some(0));
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "foo\n"
+    );
+}
+
+#[test]
+fn test_search() {
+    let test_dir = create_test_files(&[
+        ("a.txt", "foo\nbar\nfoo foo\n"),
+        ("b.txt", "bar\n"),
+        ("c.txt", "foo\n"),
+    ]);
+
+    // Without REPLACE, paths may still come from stdin.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "--compact", "-C", "0"])
+        .write_stdin("a.txt\n")
+        .assert()
+        .success()
+        .stdout("\u{1b}[31ma.txt:1>foo\n\u{1b}[0m\u{1b}[31ma.txt:3>foo foo\n\u{1b}[0m");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["-l", "foo", ".", "--sort", "path"])
+        .assert()
+        .success()
+        .stdout("./a.txt\n./c.txt\n");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["--count", "foo", ".", "--sort", "path"])
+        .assert()
+        .success()
+        .stdout("./a.txt:2\n./c.txt:1\n");
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["--search", "baz"])
+        .arg(test_dir.path())
+        .assert()
+        .code(1);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "--write"])
+        .write_stdin("a.txt\n")
+        .assert()
+        .code(2);
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "foo\nbar\nfoo foo\n"
+    );
+}
+
+fn git(dir: &TempDir, args: &[&str]) {
+    let status = std::process::Command::new("git")
+        .args(&["-c", "user.name=fnr", "-c", "user.email=fnr@localhost"])
+        .args(args)
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn create_test_repo<'a>(files: impl IntoIterator<Item = &'a (&'a str, &'a str)>) -> TempDir {
+    let test_dir = create_test_files(files);
+    git(&test_dir, &["init", "-q"]);
+    git(&test_dir, &["add", "."]);
+    git(&test_dir, &["commit", "-q", "-m", "initial"]);
+    test_dir
+}
+
+#[test]
+fn test_changed_since() {
+    let test_dir = create_test_repo(&[("a", "foo\n"), ("b", "foo\n"), ("c", "foo\n")]);
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo\n").unwrap();
+    std::fs::write(test_dir.path().join("b"), "foo\nfoo\n").unwrap();
+    git(&test_dir, &["add", "b"]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--staged", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b")).unwrap(),
+        "bar\nbar\n"
+    );
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "baz", "--changed-since", "HEAD", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "baz\nbaz\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("c")).unwrap(), "foo\n");
+}
+
+#[test]
+fn test_changed_since_walks_as_usual() {
+    let test_dir = create_test_repo(&[
+        (".gitignore", "*.log\n"),
+        ("a", "foo\n"),
+        (".hidden", "foo\n"),
+    ]);
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo\n").unwrap();
+    std::fs::write(test_dir.path().join(".hidden"), "foo\nfoo\n").unwrap();
+    std::fs::write(test_dir.path().join("ignored.log"), "foo\n").unwrap();
+    std::fs::write(test_dir.path().join("untracked"), "foo\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--changed-since", "HEAD", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "bar\nbar\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("untracked")).unwrap(),
+        "bar\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join(".hidden")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("ignored.log")).unwrap(),
+        "foo\n"
+    );
+
+    // Untracked files are new, so every line has changed.
+    std::fs::write(test_dir.path().join("untracked"), "foo\nfoo\n").unwrap();
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "baz", "--only-changed-lines", "--write", "untracked"])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("untracked")).unwrap(),
+        "baz\nbaz\n"
+    );
+}
+
+#[test]
+fn test_changed_since_from_another_directory() {
+    let test_dir = create_test_repo(&[("a", "foo\n"), ("b", "foo\n")]);
+    let other_dir = tempfile::tempdir().unwrap();
+
+    // Nothing has changed yet.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(other_dir.path())
+        .args(&[
+            "foo",
+            "bar",
+            "--changed-since",
+            "HEAD",
+            "--dry-run",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .code(1);
+
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(other_dir.path())
+        .args(&[
+            "foo",
+            "bar",
+            "--changed-since",
+            "HEAD",
+            "--write",
+            test_dir.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "bar\nbar\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+}
+
+#[test]
+fn test_only_changed_lines() {
+    let test_dir = create_test_repo(&[("a", "foo\nfoo\nfoo\nfoo\n")]);
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo 2\nfoo 3\nfoo\nfoo 5\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--only-changed-lines", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nbar 2\nbar 3\nfoo\nbar 5\n"
+    );
+}
+
+#[test]
+fn test_rename_paths_with_git_mv() {
+    let test_dir = create_test_repo(&[("widget.rs", "widget\n")]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&[
+            "widget",
+            "gadget",
+            "--rename-paths",
+            "--git-mv",
+            "--write",
+            ".",
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("gadget.rs")).unwrap(),
+        "gadget\n"
+    );
+    let output = std::process::Command::new("git")
+        .args(&["ls-files"])
+        .current_dir(test_dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "gadget.rs\n");
+
+    // Outside of a repository, git reports the failure.
+    let test_dir = create_test_files(&[("widget.rs", "")]);
+    let assert = Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&[
+            "widget",
+            "gadget",
+            "--rename-paths",
+            "--git-mv",
+            "--write",
+            ".",
+        ])
+        .assert()
+        .code(2);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("git failed"));
+    assert!(test_dir.path().join("widget.rs").exists());
+}
+
+#[test]
+fn test_write_skips_dirty_files() {
+    let test_dir = create_test_repo(&[("a", "foo\n"), ("b", "foo\n")]);
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo\n").unwrap();
+
+    // Nothing is written, even to clean files.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--write", "--dirty=abort", "."])
+        .assert()
+        .failure();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+
+    // The prompt isn't shown for dirty files.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "baz", "--sort=path", "."])
+        .write_stdin("a\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "baz\n");
+    git(&test_dir, &["checkout", "b"]);
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "bar\n");
+}
+
+#[test]
+fn test_write_skips_untracked_files() {
+    let test_dir = create_test_repo(&[("a", "foo\n")]);
+    std::fs::write(test_dir.path().join("b"), "foo\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(read_to_string(test_dir.path().join("a")).unwrap(), "bar\n");
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+}
+
+#[test]
+fn test_dirty_check_errors_skip_only_that_file() {
+    let test_dir = create_test_files(&[("a", "foo\n"), ("c", "foo\n")]);
+    let broken = test_dir.path().join("b");
+    std::fs::create_dir(&broken).unwrap();
+    std::fs::write(broken.join(".git"), "not a repository\n").unwrap();
+    std::fs::write(broken.join("x"), "foo\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--sort", "path"])
+        .arg(test_dir.path())
+        .write_stdin("y\ny\n")
+        .assert()
+        .code(2);
+
+    assert_eq!(read_to_string(test_dir.path().join("a")).unwrap(), "bar\n");
+    assert_eq!(read_to_string(broken.join("x")).unwrap(), "foo\n");
+    assert_eq!(read_to_string(test_dir.path().join("c")).unwrap(), "bar\n");
+}
+
+#[test]
+fn test_dirty_files_keep_max_total() {
+    let test_dir = create_test_repo(&[("a", "foo\n"), ("b", "foo\n")]);
+    std::fs::write(test_dir.path().join("a"), "foo\nfoo\n").unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&[
+            "foo",
+            "bar",
+            "--max-total",
+            "1",
+            "--sort",
+            "path",
+            "--write",
+            ".",
+        ])
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nfoo\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "bar\n");
+    git(&test_dir, &["checkout", "b"]);
+
+    // As when searching in parallel.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .current_dir(test_dir.path())
+        .args(&["foo", "bar", "--max-total", "1", "--write", "."])
+        .assert()
+        .success();
+
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "bar\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_edit_replacement_in_editor() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = create_test_files(&[
+        ("a", "foo\nunrelated\n"),
+        (
+            "editor.sh",
+            "#!/bin/sh\nsed -i.bak 's/^bar$/baz\\nqux/' \"$2\"\n",
+        ),
+    ]);
+    let editor = test_dir.path().join("editor.sh");
+    std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("EDITOR", &editor)
+        .env_remove("VISUAL")
+        .args(&["foo", "bar", test_dir.path().join("a").to_str().unwrap()])
+        .write_stdin("e\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "baz\nqux\nunrelated\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_edit_structural_replacement_in_editor() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = create_test_files(&[
+        ("a", "call(\n    x,\n);\nunrelated\n"),
+        (
+            "editor.sh",
+            "#!/bin/sh\ncp \"$2\" \"$(dirname \"$0\")/staged\"\nsed -i.bak 's/^done($/edited(/' \"$2\"\n",
+        ),
+    ]);
+    let editor = test_dir.path().join("editor.sh");
+    std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .env("EDITOR", &editor)
+        .env_remove("VISUAL")
+        .args(&[
+            "--structural",
+            "call(:[args])",
+            "done(:[args])",
+            test_dir.path().join("a").to_str().unwrap(),
+        ])
+        .write_stdin("e\n")
+        .assert()
+        .success();
+
+    // The replacement stands in for every line of the match.
+    assert_eq!(
+        read_to_string(test_dir.path().join("staged")).unwrap(),
+        "done(\n    x,\n);\nunrelated\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "edited(\n    x,\n);\nunrelated\n"
+    );
+}
+
+#[test]
+fn test_prompt_reads_lines_without_terminal() {
+    let test_dir = create_test_files(&[("a", "foo\n"), ("b", "foo\n"), ("c", "foo\n")]);
+
+    // Without a terminal, each answer is a line rather than a key.
+    // Unknown answers show the help and ask again.
+    let assert = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", "--sort", "path"])
+        .arg(test_dir.path())
+        .write_stdin("x\ny\nn\ny\n")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(stdout.contains("? - show help"));
+
+    assert_eq!(read_to_string(test_dir.path().join("a")).unwrap(), "bar\n");
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+    assert_eq!(read_to_string(test_dir.path().join("c")).unwrap(), "bar\n");
+
+    // Running out of answers stops as with `q`.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo|bar", "baz", "--sort", "path"])
+        .arg(test_dir.path())
+        .write_stdin("y\n")
+        .assert()
+        .success();
+
+    assert_eq!(read_to_string(test_dir.path().join("a")).unwrap(), "baz\n");
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+    assert_eq!(read_to_string(test_dir.path().join("c")).unwrap(), "bar\n");
+}
+
+#[test]
+fn test_prompt_commands() {
+    let test_dir = create_test_files(&[
+        ("a", "foo\nfoo\nfoo\n"),
+        ("b", "foo\nfoo\n"),
+        ("c", "foo\n"),
+    ]);
+    let path = |name| test_dir.path().join(name).to_str().unwrap().to_owned();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", &path("a"), &path("b"), &path("c")])
+        // a: accept, undo, reject, accept, skip rest of file
+        // b: accept all remaining in all files
+        .write_stdin("y\nu\nn\ny\ns\nA\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo\nbar\nfoo\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b")).unwrap(),
+        "bar\nbar\n"
+    );
+    assert_eq!(read_to_string(test_dir.path().join("c")).unwrap(), "bar\n");
+}
+
+#[test]
+fn test_prompt_more_context() {
+    let test_dir = create_test_files(&[("a", "1\n2\n3\n4\nfoo\n6\n7\n8\n9\n")]);
+
+    let output = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&[
+            "foo",
+            "bar",
+            "-C",
+            "0",
+            test_dir.path().join("a").to_str().unwrap(),
+        ])
+        .write_stdin("m\ny\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The match is shown again with surrounding lines.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("    1 1\n    2 2\n    3 3\n    4 4\n"));
+    assert!(stdout.contains("    6 6\n    7 7\n    8 8\n    9 9\n"));
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "1\n2\n3\n4\nbar\n6\n7\n8\n9\n"
+    );
+}
+
+#[test]
+fn test_prompt_seek() {
+    let test_dir = create_test_files(&[("a", "foo 1\nfoo 2\nfoo 3\n"), ("b", "foo 4\nfoo 5\n")]);
+    let path = |name| test_dir.path().join(name).to_str().unwrap().to_owned();
+
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", &path("a"), &path("b")])
+        // a: skip to "3" and accept it
+        // b: reject both
+        .write_stdin("/3\ny\nn\nn\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo 1\nfoo 2\nbar 3\n"
+    );
+
+    let output = Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", &path("a"), &path("b")])
+        // a: search for "9", which isn't there
+        // b: prompted again from the start
+        .write_stdin("/9\ny\nn\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("no more matches containing \"9\" in this file"));
+
+    assert_eq!(
+        read_to_string(test_dir.path().join("a")).unwrap(),
+        "foo 1\nfoo 2\nbar 3\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b")).unwrap(),
+        "bar 4\nfoo 5\n"
+    );
+}
+
+#[test]
+fn test_prompt_out_of_answers() {
+    let test_dir = create_test_files(&[("a", "foo\nfoo\n"), ("b", "foo\n")]);
+    let path = |name| test_dir.path().join(name).to_str().unwrap().to_owned();
+
+    // Running out of answers stops as with q, rather than waiting
+    // forever.
+    Command::cargo_bin("fnr")
+        .unwrap()
+        .args(&["foo", "bar", &path("a"), &path("b")])
+        .write_stdin("y\n")
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .success();
+
+    assert_eq!(read_to_string(test_dir.path().join("b")).unwrap(), "foo\n");
+}
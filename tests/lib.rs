@@ -0,0 +1,247 @@
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use fnr::{Decision, Event, FindAndReplace, MatchPrintMode, Proposal, SortPreference};
+use tempfile::TempDir;
+
+fn create_test_files<'a>(files: impl IntoIterator<Item = &'a (&'a str, &'a str)>) -> TempDir {
+    let test_dir = tempfile::tempdir().unwrap();
+    for (name, contents) in files {
+        let path = test_dir.path().join(name);
+        let mut file = File::create(path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+    test_dir
+}
+
+#[test]
+fn test_dry_run_by_default() {
+    let test_dir = create_test_files(&[("a.txt", "foo\n")]);
+
+    let stats = FindAndReplace::new("foo", "bar")
+        .path(test_dir.path())
+        .print_mode(MatchPrintMode::Silent)
+        .run()
+        .unwrap();
+
+    assert_eq!(stats.num_matches(), 1);
+    assert_eq!(stats.num_replacements(), 0);
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "foo\n"
+    );
+}
+
+#[test]
+fn test_search_never_writes() {
+    let test_dir = create_test_files(&[("a.txt", "foo\n"), ("b.txt", "foo foo\nfoo\n")]);
+
+    let stats = FindAndReplace::search("foo")
+        .path(test_dir.path())
+        .print_mode(MatchPrintMode::Silent)
+        .write()
+        .run()
+        .unwrap();
+
+    assert_eq!(stats.num_matches(), 3);
+    assert_eq!(stats.files_with_matches(), 2);
+    assert_eq!(stats.num_replacements(), 0);
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.txt")).unwrap(),
+        "foo foo\nfoo\n"
+    );
+}
+
+#[test]
+fn test_filter() {
+    let mut output = vec![];
+    let stats = FindAndReplace::new("foo", "bar")
+        .filter("foo\nbaz\nfoo foo\n".as_bytes(), &mut output)
+        .unwrap();
+
+    assert_eq!(stats.num_matches(), 2);
+    assert_eq!(String::from_utf8(output).unwrap(), "bar\nbaz\nbar bar\n");
+}
+
+#[test]
+fn test_filter_counts_changed_lines() {
+    let mut output = vec![];
+    let stats = FindAndReplace::new("foo", "bar")
+        .occurrence(2)
+        .filter("foo\nfoo foo\n".as_bytes(), &mut output)
+        .unwrap();
+
+    assert_eq!(stats.num_matches(), 1);
+    assert_eq!(String::from_utf8(output).unwrap(), "foo\nfoo bar\n");
+}
+
+#[test]
+fn test_filter_replaces_invalid_utf8() {
+    let mut output = vec![];
+    FindAndReplace::new("foo", "bar")
+        .insert_after("after")
+        .filter(&b"foo \xff\nbaz \xfe\n"[..], &mut output)
+        .unwrap();
+
+    assert_eq!(output, b"bar \xff\nafter\nbaz \xfe\n");
+}
+
+#[test]
+fn test_filter_rejects_file_options() {
+    let err = FindAndReplace::new("foo", "bar")
+        .include("*.rs")
+        .filter("foo\n".as_bytes(), vec![])
+        .unwrap_err();
+    assert!(err.to_string().contains("include"));
+
+    assert!(FindAndReplace::new("foo", "bar")
+        .sort(SortPreference::Path)
+        .filter("foo\n".as_bytes(), vec![])
+        .is_err());
+}
+
+#[test]
+fn test_decide_with_callback() {
+    let test_dir = create_test_files(&[("a.txt", "foo 1\nfoo 2\nfoo 3\n"), ("b.txt", "foo 4\n")]);
+
+    let stats = FindAndReplace::new(r"foo (\d)", "bar $1")
+        .path(test_dir.path())
+        .print_mode(MatchPrintMode::Silent)
+        .decide_with(|proposal| match proposal {
+            Proposal::Replace { search_match, .. } if search_match.line.1.contains('2') => {
+                Decision::Ignore
+            }
+            _ => Decision::Accept,
+        })
+        .run()
+        .unwrap();
+
+    assert_eq!(stats.num_matches(), 4);
+    assert_eq!(stats.num_replacements(), 3);
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "bar 1\nfoo 2\nbar 3\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.txt")).unwrap(),
+        "bar 4\n"
+    );
+}
+
+#[test]
+fn test_events() {
+    let test_dir = create_test_files(&[("a.txt", "foo\nfoo\n"), ("b.txt", "foo\n")]);
+
+    let replaced = Arc::new(Mutex::new(vec![]));
+    let sink = replaced.clone();
+    FindAndReplace::new("foo", "bar")
+        .path(test_dir.path())
+        .write()
+        .sort(SortPreference::Path)
+        .events(move |event: Event<'_>| {
+            if let Event::Replaced { path, num_replaced } = event {
+                let name = PathBuf::from(path.file_name().unwrap());
+                sink.lock().unwrap().push((name, num_replaced));
+            }
+            Ok(())
+        })
+        .run()
+        .unwrap();
+
+    assert_eq!(
+        *replaced.lock().unwrap(),
+        vec![(PathBuf::from("a.txt"), 2), (PathBuf::from("b.txt"), 1)]
+    );
+}
+
+#[test]
+fn test_events_report_skipped_renames() {
+    let test_dir = create_test_files(&[("foo.txt", "a\n"), ("bar.txt", "b\n")]);
+
+    let skipped = Arc::new(Mutex::new(vec![]));
+    let sink = skipped.clone();
+    let stats = FindAndReplace::new("foo", "bar")
+        .path(test_dir.path())
+        .rename_paths(true)
+        .write()
+        .events(move |event: Event<'_>| {
+            if let Event::Skipped { path, reason } = event {
+                let name = PathBuf::from(path.file_name().unwrap());
+                sink.lock().unwrap().push((name, reason.to_owned()));
+            }
+            Ok(())
+        })
+        .run()
+        .unwrap();
+
+    assert_eq!(stats.num_renames(), 0);
+    assert_eq!(
+        *skipped.lock().unwrap(),
+        vec![(
+            PathBuf::from("foo.txt"),
+            format!(
+                "not renamed, {} already exists",
+                test_dir.path().join("bar.txt").display()
+            )
+        )]
+    );
+}
+
+#[test]
+fn test_decide_with_terminate() {
+    let test_dir = create_test_files(&[("a.txt", "foo 1\n"), ("b.txt", "foo 2\nfoo 3\n")]);
+
+    let stats = FindAndReplace::new(r"foo (\d)", "bar $1")
+        .path(test_dir.path())
+        .print_mode(MatchPrintMode::Silent)
+        .sort(SortPreference::Path)
+        .decide_with(|proposal| match proposal {
+            Proposal::Replace { search_match, .. } if search_match.line.1.contains('3') => {
+                Decision::Terminate
+            }
+            _ => Decision::Accept,
+        })
+        .run()
+        .unwrap();
+
+    assert_eq!(stats.num_replacements(), 1);
+    assert_eq!(
+        read_to_string(test_dir.path().join("a.txt")).unwrap(),
+        "bar 1\n"
+    );
+    assert_eq!(
+        read_to_string(test_dir.path().join("b.txt")).unwrap(),
+        "foo 2\nfoo 3\n"
+    );
+}
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn test_ts_query_rejects_line_options() {
+    let test_dir = create_test_files(&[("a.rs", "fn main() { foo(1); }\n")]);
+    let query = "(call_expression function: (identifier) @f (#eq? @f \"foo\")) @call";
+
+    let err = FindAndReplace::new(query, "bar()")
+        .path(test_dir.path())
+        .ts_query(true)
+        .lines(1..=1)
+        .run()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "lines can't be used with a tree-sitter query"
+    );
+
+    let err = FindAndReplace::new(query, "bar()")
+        .path(test_dir.path())
+        .ts_query(true)
+        .scope(fnr::Scope::Code)
+        .run()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "scope can't be used with a tree-sitter query"
+    );
+}